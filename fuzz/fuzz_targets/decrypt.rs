@@ -0,0 +1,35 @@
+//! Decrypts arbitrary bytes with the password "neko", through the serial,
+//! parallel and random access paths.
+//!
+//! Without a matching password hash nothing past the header is reached, so
+//! seed it with the test vectors, which use that password:
+//! `cargo fuzz run decrypt fuzz/corpus/decrypt tests/vectors`.
+
+#![no_main]
+
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use encryp::{decrypt_parallel, decrypt_reader, decrypt_stream, encryp_option_builder};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let opt = encryp_option_builder::new("neko")
+        .buffer_size(64)
+        .threads(2)
+        .build()
+        .unwrap();
+
+    let _ = decrypt_stream(&mut &data[..], &mut std::io::sink(), &opt);
+
+    let _ = decrypt_parallel(&mut Cursor::new(data), &mut std::io::sink(), &opt);
+
+    if let Ok(mut reader) = decrypt_reader::new(Cursor::new(data), &opt) {
+        let _ = std::io::copy(&mut reader, &mut std::io::sink());
+        for pos in [SeekFrom::Start(1), SeekFrom::End(-1), SeekFrom::Current(-8)] {
+            if reader.seek(pos).is_ok() {
+                let mut buf = [0_u8; 16];
+                let _ = reader.read(&mut buf);
+            }
+        }
+    }
+});
@@ -0,0 +1,9 @@
+//! Parses arbitrary bytes as a container, without a password.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = encryp::inspect_stream(&mut &data[..]);
+});
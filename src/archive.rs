@@ -0,0 +1,588 @@
+use std::fs;
+use std::io::prelude::*;
+use std::path;
+
+use sha3::Digest;
+
+use crate::{
+    ciphertext_reader, ciphertext_writer, data_block_data, data_block_type, derive_key,
+    encryp_option, get_small_block, keystream, mac, padding_policy, parse_encrypted_file,
+    read_error, unlock, write_data_block, write_data_block_head, write_error, write_header,
+};
+
+#[repr(u64)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum archive_entry_kind {
+    file = 0,
+    directory = 1,
+}
+
+/// A file or directory stored in an archive.
+///
+/// The payload of an archive holds one record per file: its content followed
+/// by its hash, see `member_digest`. Every record is encrypted with its own
+/// keystream, derived from the position of the entry in the index, so that a
+/// single member can be decrypted without the ones in front of it.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone)]
+pub struct archive_entry {
+    pub kind: archive_entry_kind,
+    /// Path inside the archive, components are separated by `/`.
+    pub path: String,
+    pub size: u64,
+    /// Offset of the record within the payload.
+    offset: u64,
+    /// Position in the index, selects the keystream of the record.
+    index: u64,
+}
+
+#[allow(non_upper_case_globals)]
+const hash_len: u64 = 64;
+
+fn member_context(index: u64) -> Vec<u8> {
+    let mut ret: Vec<u8> = Vec::from(b"member".as_slice());
+    ret.extend_from_slice(index.to_le_bytes().as_slice());
+    return ret;
+}
+
+/// Hash of the record of the entry at `index`. Authenticated archives key it
+/// with the MAC key and bind it to the position of the entry, so a member
+/// can be checked without reading the rest of the archive. Archives in the
+/// legacy format keep the sha3-512 of the content.
+fn member_digest(opt: &encryp_option, index: u64) -> mac::segment_digest {
+    let key = mac::mac_key(opt);
+    let mut ret = mac::segment_digest::new(key.as_deref());
+    if key.is_some() {
+        ret.update(&member_context(index));
+    }
+    return ret;
+}
+
+fn add_entries(
+    ret: &mut Vec<(archive_entry, path::PathBuf)>,
+    src: &path::Path,
+    name: String,
+) -> Result<(), String> {
+    // links are not followed, they could loop or lead out of the tree
+    let meta =
+        fs::symlink_metadata(src).map_err(|e| format!("Failed to read {:?} : {}", src, e))?;
+    if meta.file_type().is_symlink() {
+        return Err(format!("Refusing to archive symbolic link {:?}.", src));
+    }
+
+    let kind = if meta.is_dir() {
+        archive_entry_kind::directory
+    } else {
+        archive_entry_kind::file
+    };
+
+    ret.push((
+        archive_entry {
+            kind,
+            path: name.clone(),
+            size: if meta.is_dir() { 0 } else { meta.len() },
+            offset: 0,
+            index: ret.len() as u64,
+        },
+        src.to_path_buf(),
+    ));
+
+    if !meta.is_dir() {
+        return Ok(());
+    }
+
+    let entries = fs::read_dir(src).map_err(|e| format!("Failed to read {:?} : {}", src, e))?;
+    let mut children: Vec<path::PathBuf> = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read {:?} : {}", src, e))?;
+        children.push(entry.path());
+    }
+    children.sort();
+
+    for child in children {
+        let child_name = match child.file_name().and_then(|n| n.to_str()) {
+            Some(n) => n,
+            None => return Err(format!("Path {:?} is not valid UTF-8.", child)),
+        };
+        add_entries(ret, &child, format!("{}/{}", name, child_name))?;
+    }
+
+    return Ok(());
+}
+
+/// Collects the entries to pack from `inputs` together with their sources.
+/// Directories are stored under their own name, like `tar` does.
+fn collect_entries(inputs: &[String]) -> Result<Vec<(archive_entry, path::PathBuf)>, String> {
+    let mut ret: Vec<(archive_entry, path::PathBuf)> = Vec::new();
+
+    for input in inputs {
+        let src = path::Path::new(input);
+        let name = match src.file_name().and_then(|n| n.to_str()) {
+            Some(n) => String::from(n),
+            None => return Err(format!("Can not archive {}.", input)),
+        };
+        add_entries(&mut ret, src, name)?;
+    }
+
+    let mut offset: u64 = 0;
+    for (entry, _) in ret.iter_mut() {
+        if entry.kind == archive_entry_kind::file {
+            entry.offset = offset;
+            offset += entry.size + hash_len;
+        }
+    }
+
+    return Ok(ret);
+}
+
+/// Serializes the index, little-endian throughout: the length of the entry
+/// list, the entries, the sha3-512 of the entry list, then padding zeros.
+fn encode_index(entries: &[archive_entry], policy: padding_policy) -> Vec<u8> {
+    let mut list: Vec<u8> = Vec::new();
+    for entry in entries {
+        list.extend_from_slice((entry.kind as u64).to_le_bytes().as_slice());
+        list.extend_from_slice((entry.path.len() as u64).to_le_bytes().as_slice());
+        list.extend_from_slice(entry.path.as_bytes());
+        list.extend_from_slice(entry.offset.to_le_bytes().as_slice());
+        list.extend_from_slice(entry.size.to_le_bytes().as_slice());
+    }
+
+    let mut ret: Vec<u8> = Vec::new();
+    ret.extend_from_slice((list.len() as u64).to_le_bytes().as_slice());
+    ret.extend_from_slice(&list);
+    ret.extend_from_slice(&sha3::Sha3_512::digest(&list));
+
+    let padded_len = policy.padded_len(ret.len() as u64) as usize;
+    ret.resize(padded_len, 0);
+
+    return ret;
+}
+
+/// Reads the fields of a decrypted index, failing on anything out of bounds.
+#[allow(non_camel_case_types)]
+struct index_reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> index_reader<'a> {
+    fn take(&mut self, len: u64) -> Result<&'a [u8], String> {
+        let len = match usize::try_from(len) {
+            Ok(len) if self.data.len() - self.pos >= len => len,
+            _ => return Err(String::from("Malformed archive index.")),
+        };
+        self.pos += len;
+        return Ok(&self.data[(self.pos - len)..self.pos]);
+    }
+
+    fn take_u64(&mut self) -> Result<u64, String> {
+        let mut v = [0_u8; 8];
+        v.copy_from_slice(self.take(8)?);
+        return Ok(u64::from_le_bytes(v));
+    }
+
+    fn finished(&self) -> bool {
+        return self.pos == self.data.len();
+    }
+}
+
+fn decode_index(data: &[u8]) -> Result<Vec<archive_entry>, String> {
+    let mut reader = index_reader { data, pos: 0 };
+
+    let list_len = reader.take_u64()?;
+    let list = reader.take(list_len)?;
+    let hash = reader.take(hash_len)?;
+
+    if sha3::Sha3_512::digest(list).as_slice() != hash {
+        return Err(String::from("Archive index checksum failed."));
+    }
+
+    let mut ret: Vec<archive_entry> = Vec::new();
+
+    let mut reader = index_reader { data: list, pos: 0 };
+    while !reader.finished() {
+        let kind = match reader.take_u64()? {
+            0 => archive_entry_kind::file,
+            1 => archive_entry_kind::directory,
+            _ => return Err(String::from("Malformed archive index.")),
+        };
+        let path_len = reader.take_u64()?;
+        let path = match String::from_utf8(reader.take(path_len)?.to_vec()) {
+            Ok(path) => path,
+            Err(_) => return Err(String::from("Malformed archive index.")),
+        };
+        let offset = reader.take_u64()?;
+        let size = reader.take_u64()?;
+
+        ret.push(archive_entry {
+            kind,
+            path,
+            size,
+            offset,
+            index: ret.len() as u64,
+        });
+    }
+
+    return Ok(ret);
+}
+
+/// Packs `inputs` into an archive written to `ofile`.
+pub fn pack_stream<W: Write>(
+    inputs: &[String],
+    ofile: &mut W,
+    opt: &encryp_option,
+) -> Result<(), String> {
+    opt.validate()?;
+    let entries = collect_entries(inputs)?;
+    return pack_entries(&entries, ofile, opt);
+}
+
+fn pack_entries<W: Write>(
+    entries: &[(archive_entry, path::PathBuf)],
+    ofile: &mut W,
+    opt: &encryp_option,
+) -> Result<(), String> {
+    let index: Vec<archive_entry> = entries.iter().map(|(e, _)| e.clone()).collect();
+
+    let opt = &derive_key(opt)?;
+    let ofile = &mut mac::mac_writer::new(ofile, opt);
+
+    write_header(ofile, opt)?;
+
+    let mut index_ciphertext: Vec<u8> = Vec::new();
+    let keys = keystream::single(opt, b"index");
+    let mut cipher = ciphertext_writer::new(&mut index_ciphertext, keys, opt.buffer_size, false);
+    cipher
+        .write_all(&encode_index(&index, opt.padding))
+        .map_err(write_error)?;
+    cipher.finish().map_err(write_error)?;
+
+    write_data_block(ofile, data_block_type::archive_index, &index_ciphertext)
+        .map_err(write_error)?;
+
+    ofile.write_header_tag().map_err(write_error)?;
+
+    let payload_len: u64 = index
+        .iter()
+        .filter(|e| e.kind == archive_entry_kind::file)
+        .map(|e| e.size + hash_len)
+        .sum();
+    let padded_len: u64 = opt.padding.padded_len(payload_len);
+
+    write_data_block_head(ofile, data_block_type::archive_payload, padded_len)
+        .map_err(write_error)?;
+
+    let mut buffer: Vec<u8> = vec![0; opt.buffer_size.max(1)];
+
+    for (entry, src) in entries {
+        if entry.kind != archive_entry_kind::file {
+            continue;
+        }
+
+        let mut ifile =
+            fs::File::open(src).map_err(|e| format!("Failed to open {:?} : {}", src, e))?;
+
+        let keys = keystream::single(opt, &member_context(entry.index));
+        let mut cipher = ciphertext_writer::new(ofile, keys, opt.buffer_size, false);
+        let mut hasher = member_digest(opt, entry.index);
+
+        let mut copied: u64 = 0;
+        loop {
+            let read_bytes = ifile.read(&mut buffer).map_err(read_error)?;
+            if read_bytes == 0 {
+                break;
+            }
+            copied += read_bytes as u64;
+            if copied > entry.size {
+                break;
+            }
+
+            hasher.update(&buffer[0..read_bytes]);
+            cipher
+                .write_all(&buffer[0..read_bytes])
+                .map_err(write_error)?;
+        }
+
+        if copied != entry.size {
+            return Err(format!("{:?} changed while packing.", src));
+        }
+
+        cipher.write_all(&hasher.finalize()).map_err(write_error)?;
+        cipher.finish().map_err(write_error)?;
+    }
+
+    // encrypted like the members, so the end of the real data can not be seen
+    let keys = keystream::single(opt, b"padding");
+    let mut cipher = ciphertext_writer::new(ofile, keys, opt.buffer_size, false);
+    let mut padding_left: u64 = padded_len - payload_len;
+    buffer.fill(0);
+    while padding_left > 0 {
+        let bytes = std::cmp::min(buffer.len() as u64, padding_left) as usize;
+        cipher.write_all(&buffer[0..bytes]).map_err(write_error)?;
+        padding_left -= bytes as u64;
+    }
+    cipher.finish().map_err(write_error)?;
+
+    ofile.finish().map_err(write_error)?;
+    ofile.flush().map_err(write_error)?;
+
+    return Ok(());
+}
+
+/// Packs files and directories into the archive `dst_name`.
+pub fn pack_archive(
+    inputs: &[String],
+    dst_name: &String,
+    opt: &encryp_option,
+) -> Result<(), String> {
+    opt.validate()?;
+
+    // collected before the archive exists, so it never packs itself
+    let entries = collect_entries(inputs)?;
+
+    let mut ofile = fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .create_new(!opt.cover_existing_file)
+        .open(dst_name)
+        .map_err(|e| format!("Failed to create {} : {}", dst_name, e))?;
+
+    let ret = pack_entries(&entries, &mut ofile, opt);
+    if ret.is_err() {
+        drop(ofile);
+        let _ = fs::remove_file(dst_name);
+    }
+    return ret;
+}
+
+/// Checks the password and decrypts the index. Returns the unlocked option,
+/// the entries and the offset and length of the payload in the file.
+fn open_archive<R: Read>(
+    ifile: &mut R,
+    opt: &encryp_option,
+) -> Result<(encryp_option, Vec<archive_entry>, u64, u64), String> {
+    opt.validate()?;
+
+    let efile = parse_encrypted_file(ifile)?;
+
+    if !efile
+        .data_blocks
+        .contains_key(&data_block_type::archive_index)
+    {
+        return Err(String::from("File is not an archive."));
+    }
+
+    let opt = unlock(&efile, opt)?;
+
+    let index_ciphertext = get_small_block(&efile, data_block_type::archive_index)?;
+
+    let (payload_offset, payload_len) = match efile
+        .data_blocks
+        .get(&data_block_type::archive_payload)
+    {
+        Some(content) => match content.data {
+            data_block_data::large(len) => (content.offset, len),
+            data_block_data::small(_) => return Err(String::from("Malformed archive payload.")),
+        },
+        None => return Err(String::from("Archive payload not found.")),
+    };
+
+    let mut index: Vec<u8> = Vec::new();
+    {
+        let mut slice: &[u8] = &index_ciphertext;
+        let keys = keystream::single(&opt, b"index");
+        let mut cipher = ciphertext_reader::new(
+            &mut slice,
+            keys,
+            opt.buffer_size,
+            Some(index_ciphertext.len() as u64),
+        );
+        cipher.read_to_end(&mut index).map_err(read_error)?;
+    }
+
+    let entries = decode_index(&index)?;
+    if entries
+        .iter()
+        .any(|e| payload_offset.checked_add(e.offset).is_none())
+    {
+        return Err(String::from("Malformed archive index."));
+    }
+
+    return Ok((opt, entries, payload_offset, payload_len));
+}
+
+/// Lists the entries of the archive `src_name`.
+pub fn list_archive(src_name: &String, opt: &encryp_option) -> Result<Vec<archive_entry>, String> {
+    let mut ifile =
+        fs::File::open(src_name).map_err(|e| format!("Failed to open {} : {}", src_name, e))?;
+
+    let (_, entries, _, _) = open_archive(&mut ifile, opt)?;
+    return Ok(entries);
+}
+
+/// Decrypts the record of `entry` at the current position of `ifile`.
+fn extract_member<R: Read, W: Write>(
+    ifile: &mut R,
+    ofile: &mut W,
+    opt: &encryp_option,
+    entry: &archive_entry,
+) -> Result<(), String> {
+    let keys = keystream::single(opt, &member_context(entry.index));
+    let record_len = match entry.size.checked_add(hash_len) {
+        Some(len) => len,
+        None => return Err(String::from("Malformed archive index.")),
+    };
+    let mut cipher = ciphertext_reader::new(ifile, keys, opt.buffer_size, Some(record_len));
+
+    let mut hasher = member_digest(opt, entry.index);
+    let mut buffer: Vec<u8> = vec![0; opt.buffer_size.max(1)];
+
+    {
+        let mut content = (&mut cipher).take(entry.size);
+        loop {
+            let bytes_read = content.read(&mut buffer).map_err(read_error)?;
+            if bytes_read == 0 {
+                break;
+            }
+            hasher.update(&buffer[0..bytes_read]);
+            ofile
+                .write_all(&buffer[0..bytes_read])
+                .map_err(write_error)?;
+        }
+    }
+
+    let mut hash = [0_u8; hash_len as usize];
+    cipher.read_exact(&mut hash).map_err(read_error)?;
+
+    if hasher.finalize().as_slice() != hash.as_slice() {
+        return Err(format!("Checksum of {} failed.", entry.path));
+    }
+
+    ofile.flush().map_err(write_error)?;
+    return Ok(());
+}
+
+/// Joins `dst_dir` and an archive path, refusing paths that would escape it.
+fn member_path(dst_dir: &path::Path, name: &str) -> Result<path::PathBuf, String> {
+    let mut ret = dst_dir.to_path_buf();
+
+    for part in name.split('/') {
+        let mut components = path::Path::new(part).components();
+        match (components.next(), components.next()) {
+            (Some(path::Component::Normal(_)), None) => ret.push(part),
+            _ => return Err(format!("Refusing to extract unsafe path {}.", name)),
+        }
+    }
+
+    return Ok(ret);
+}
+
+/// Extracts `members` of the archive `src_name` into `dst_dir`, or every
+/// entry if `members` is empty. A member names a file or a whole directory.
+///
+/// Only the records of the selected members are read. Extracting every entry
+/// reads the archive twice, as its MAC is checked before anything is written.
+pub fn unpack_archive(
+    src_name: &String,
+    dst_dir: &String,
+    members: &[String],
+    opt: &encryp_option,
+) -> Result<(), String> {
+    let mut ifile =
+        fs::File::open(src_name).map_err(|e| format!("Failed to open {} : {}", src_name, e))?;
+
+    let (opt, entries, payload_offset, payload_len) = open_archive(&mut ifile, opt)?;
+    // single members are covered by the header tag over the index and by
+    // their keyed hashes
+    if members.is_empty() {
+        mac::verify_file(&mut ifile, &opt, payload_len)?;
+    }
+
+    let selected = |entry: &archive_entry| -> bool {
+        if members.is_empty() {
+            return true;
+        }
+        return members.iter().any(|m| {
+            let m = m.trim_end_matches('/');
+            entry.path == m || entry.path.starts_with(&format!("{}/", m))
+        });
+    };
+
+    for m in members {
+        let m = m.trim_end_matches('/');
+        if !entries
+            .iter()
+            .any(|e| e.path == m || e.path.starts_with(&format!("{}/", m)))
+        {
+            return Err(format!("{} is not in the archive.", m));
+        }
+    }
+
+    for entry in entries.iter().filter(|e| selected(e)) {
+        let target = member_path(path::Path::new(dst_dir), &entry.path)?;
+
+        if entry.kind == archive_entry_kind::directory {
+            fs::create_dir_all(&target)
+                .map_err(|e| format!("Failed to create {:?} : {}", target, e))?;
+            continue;
+        }
+
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| format!("Failed to create {:?} : {}", parent, e))?;
+        }
+
+        let mut ofile = fs::OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .create_new(!opt.cover_existing_file)
+            .open(&target)
+            .map_err(|e| format!("Failed to create {:?} : {}", target, e))?;
+
+        ifile
+            .seek(std::io::SeekFrom::Start(payload_offset + entry.offset))
+            .map_err(read_error)?;
+
+        if let Err(err) = extract_member(&mut ifile, &mut ofile, &opt, entry) {
+            drop(ofile);
+            let _ = fs::remove_file(&target);
+            return Err(err);
+        }
+    }
+
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn member_path_refuses_unsafe_names() {
+        let dst = path::Path::new("out");
+        for name in [
+            "../evil",
+            "dir/../../evil",
+            "/etc/passwd",
+            "dir//file",
+            "./file",
+            "dir/.",
+            "",
+        ] {
+            assert!(member_path(dst, name).is_err(), "{}", name);
+        }
+    }
+
+    #[test]
+    fn member_path_keeps_nested_names_under_the_destination() {
+        let dst = path::Path::new("out");
+        assert_eq!(
+            member_path(dst, "dir/sub/file").unwrap(),
+            dst.join("dir").join("sub").join("file")
+        );
+        assert_eq!(member_path(dst, "file..").unwrap(), dst.join("file.."));
+    }
+}
@@ -0,0 +1,410 @@
+use std::future::Future;
+use std::io::prelude::*;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::{
+    decrypt_stream, encryp_error, encryp_option, encryp_parallel, encryp_stream, file_result,
+    write_error,
+};
+
+/// Number of buffers in flight between the async side and the worker.
+#[allow(non_upper_case_globals)]
+const channel_capacity: usize = 4;
+
+/// Source of the blocking worker, fed by `run_blocking`.
+#[allow(non_camel_case_types)]
+struct channel_reader {
+    receiver: mpsc::Receiver<std::io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    begin: usize,
+}
+
+impl Read for channel_reader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.begin == self.chunk.len() {
+            match self.receiver.blocking_recv() {
+                None => return Ok(0),
+                Some(Err(err)) => return Err(err),
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.begin = 0;
+                }
+            }
+        }
+
+        let bytes: usize = std::cmp::min(self.chunk.len() - self.begin, buf.len());
+        buf[0..bytes].copy_from_slice(&self.chunk[self.begin..(self.begin + bytes)]);
+        self.begin += bytes;
+
+        return Ok(bytes);
+    }
+}
+
+/// Destination of the blocking worker, drained by `run_blocking`.
+#[allow(non_camel_case_types)]
+struct channel_writer {
+    sender: mpsc::Sender<Vec<u8>>,
+}
+
+impl Write for channel_writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.sender.blocking_send(buf.to_vec()).is_err() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "Destination closed.",
+            ));
+        }
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// Runs `work` on a blocking thread of the runtime, feeding it from `ifile`
+/// and writing its output to `ofile`. This way the async functions share the
+/// whole block format with the sync ones, and the cipher does not stall the
+/// executor.
+async fn run_blocking<R, W, F>(
+    ifile: &mut R,
+    ofile: &mut W,
+    buffer_size: usize,
+    work: F,
+) -> Result<(), String>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnOnce(&mut channel_reader, &mut std::io::BufWriter<channel_writer>) -> Result<(), String>
+        + Send
+        + 'static,
+{
+    let buffer_size: usize = buffer_size.max(1);
+
+    let (input, receiver) = mpsc::channel::<std::io::Result<Vec<u8>>>(channel_capacity);
+    let (sender, mut output) = mpsc::channel::<Vec<u8>>(channel_capacity);
+
+    let worker = tokio::task::spawn_blocking(move || {
+        let mut ifile = channel_reader {
+            receiver,
+            chunk: Vec::new(),
+            begin: 0,
+        };
+        let mut ofile = std::io::BufWriter::with_capacity(buffer_size, channel_writer { sender });
+        return work(&mut ifile, &mut ofile);
+    });
+
+    // both end early once the worker stops, dropping their end of the channel
+    let feed = async move {
+        loop {
+            let mut chunk: Vec<u8> = vec![0; buffer_size];
+            match ifile.read(&mut chunk).await {
+                Ok(0) => return,
+                Ok(bytes) => {
+                    chunk.truncate(bytes);
+                    if input.send(Ok(chunk)).await.is_err() {
+                        return;
+                    }
+                }
+                Err(err) => {
+                    let _ = input.send(Err(err)).await;
+                    return;
+                }
+            }
+        }
+    };
+
+    let drain = async move {
+        while let Some(chunk) = output.recv().await {
+            ofile.write_all(&chunk).await.map_err(write_error)?;
+        }
+        ofile.flush().await.map_err(write_error)?;
+        return Ok::<(), String>(());
+    };
+
+    let ((), drained) = tokio::join!(feed, drain);
+
+    match worker.await {
+        Ok(result) => result?,
+        Err(err) => return Err(format!("Worker failed : {}", err)),
+    }
+
+    return drained;
+}
+
+/// Async version of `encryp_stream`, with identical output.
+pub async fn async_encryp_stream<R, W>(
+    ifile: &mut R,
+    ofile: &mut W,
+    opt: &encryp_option,
+    file_size: Option<u64>,
+) -> Result<(), String>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let opt: encryp_option = opt.clone();
+    return run_blocking(ifile, ofile, opt.buffer_size, move |ifile, ofile| {
+        return encryp_stream(ifile, ofile, &opt, file_size);
+    })
+    .await;
+}
+
+/// Async version of `decrypt_stream`. As there, the plaintext is written
+/// before it can be verified.
+pub async fn async_decrypt_stream<R, W>(
+    ifile: &mut R,
+    ofile: &mut W,
+    opt: &encryp_option,
+) -> Result<(), String>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let opt: encryp_option = opt.clone();
+    return run_blocking(ifile, ofile, opt.buffer_size, move |ifile, ofile| {
+        return decrypt_stream(ifile, ofile, &opt);
+    })
+    .await;
+}
+
+/// Opens `src_name` and creates `dst_name`, refusing what `try_encryp_file`
+/// refuses.
+async fn open_files(
+    src_name: &str,
+    dst_name: &str,
+    opt: &encryp_option,
+) -> Result<(tokio::fs::File, tokio::fs::File), String> {
+    if src_name == dst_name {
+        return Err(String::from(
+            "Error : source filename is equal to destination",
+        ));
+    }
+
+    let ifile = match tokio::fs::File::open(src_name).await {
+        Ok(file) => file,
+        Err(_) => return Err(String::from("Error : failed to open source file.")),
+    };
+
+    let ofile = tokio::fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .create(true)
+        .create_new(!opt.cover_existing_file)
+        .open(dst_name)
+        .await;
+    match ofile {
+        Ok(ofile) => return Ok((ifile, ofile)),
+        Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+            return Err(String::from("Error : destination file already exists."));
+        }
+        Err(_) => return Err(String::from("Error : failed to open/create dest file")),
+    }
+}
+
+/// Async version of `try_encryp_file`, with identical output. The files are
+/// read and written through `tokio::fs`.
+pub async fn async_encryp_file(
+    src_name: &str,
+    dst_name: &str,
+    opt: &encryp_option,
+) -> Result<(), encryp_error> {
+    opt.validate().map_err(encryp_error::failed)?;
+    let (mut ifile, mut ofile) = open_files(src_name, dst_name, opt)
+        .await
+        .map_err(encryp_error::failed)?;
+
+    let mut file_opt: encryp_option = opt.clone();
+    file_opt.progress = file_opt.progress.for_file(src_name);
+
+    let result = match ifile.metadata().await {
+        Ok(meta) => {
+            let file_size: u64 = meta.len();
+            run_blocking(
+                &mut ifile,
+                &mut ofile,
+                file_opt.buffer_size,
+                move |ifile, ofile| {
+                    if file_opt.threads > 1 {
+                        return encryp_parallel(ifile, ofile, &file_opt, file_size);
+                    }
+                    return encryp_stream(ifile, ofile, &file_opt, Some(file_size));
+                },
+            )
+            .await
+        }
+        Err(err) => Err(format!("Failed to get size of {} : {}", src_name, err)),
+    };
+
+    return file_result(result, dst_name, opt);
+}
+
+/// Async version of `try_decrypt_file`. The files are read and written
+/// through `tokio::fs`, and segmented files are decrypted on a single thread.
+pub async fn async_decrypt_file(
+    src_name: &str,
+    dst_name: &str,
+    opt: &encryp_option,
+) -> Result<(), encryp_error> {
+    opt.validate().map_err(encryp_error::failed)?;
+    let (mut ifile, mut ofile) = open_files(src_name, dst_name, opt)
+        .await
+        .map_err(encryp_error::failed)?;
+
+    let mut file_opt: encryp_option = opt.clone();
+    file_opt.progress = file_opt.progress.for_file(src_name);
+
+    let result = run_blocking(
+        &mut ifile,
+        &mut ofile,
+        file_opt.buffer_size,
+        move |ifile, ofile| {
+            return decrypt_stream(ifile, ofile, &file_opt);
+        },
+    )
+    .await;
+
+    return file_result(result, dst_name, opt);
+}
+
+fn poll_task(
+    task: &mut Option<JoinHandle<Result<(), String>>>,
+    cx: &mut Context<'_>,
+) -> Poll<std::io::Result<()>> {
+    let handle = match task {
+        Some(handle) => handle,
+        None => return Poll::Ready(Ok(())),
+    };
+
+    let result = match Pin::new(handle).poll(cx) {
+        Poll::Pending => return Poll::Pending,
+        Poll::Ready(Ok(result)) => result.map_err(std::io::Error::other),
+        Poll::Ready(Err(err)) => Err(std::io::Error::other(err)),
+    };
+    *task = None;
+
+    return Poll::Ready(result);
+}
+
+/// Encrypts everything written to it into `ofile`.
+///
+/// Must be created inside a tokio runtime. Shutting it down writes the end
+/// of the file and reports any error of the encryption.
+#[allow(non_camel_case_types)]
+pub struct async_encryp_writer {
+    input: DuplexStream,
+    task: Option<JoinHandle<Result<(), String>>>,
+}
+
+impl async_encryp_writer {
+    pub fn new<W: AsyncWrite + Unpin + Send + 'static>(
+        mut ofile: W,
+        opt: &encryp_option,
+        file_size: Option<u64>,
+    ) -> async_encryp_writer {
+        let (input, mut output) = tokio::io::duplex(opt.buffer_size.max(1));
+        let opt: encryp_option = opt.clone();
+
+        let task = tokio::spawn(async move {
+            return async_encryp_stream(&mut output, &mut ofile, &opt, file_size).await;
+        });
+
+        return async_encryp_writer {
+            input,
+            task: Some(task),
+        };
+    }
+}
+
+impl AsyncWrite for async_encryp_writer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        match Pin::new(&mut this.input).poll_write(cx, buf) {
+            // the encryption stopped, report why
+            Poll::Ready(Err(err)) => match poll_task(&mut this.task, cx) {
+                Poll::Ready(Err(task_err)) => return Poll::Ready(Err(task_err)),
+                _ => return Poll::Ready(Err(err)),
+            },
+            other => return other,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        return Pin::new(&mut self.get_mut().input).poll_flush(cx);
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if let Poll::Ready(Err(err)) = Pin::new(&mut this.input).poll_shutdown(cx) {
+            return Poll::Ready(Err(err));
+        }
+        return poll_task(&mut this.task, cx);
+    }
+}
+
+/// Reads the plaintext of the encrypted `ifile`.
+///
+/// Must be created inside a tokio runtime. The plaintext is verified only at
+/// its end, so a failed checksum is reported by the read that would return
+/// the end of the file.
+#[allow(non_camel_case_types)]
+pub struct async_decrypt_reader {
+    output: DuplexStream,
+    task: Option<JoinHandle<Result<(), String>>>,
+}
+
+impl async_decrypt_reader {
+    pub fn new<R: AsyncRead + Unpin + Send + 'static>(
+        mut ifile: R,
+        opt: &encryp_option,
+    ) -> async_decrypt_reader {
+        let (mut input, output) = tokio::io::duplex(opt.buffer_size.max(1));
+        let opt: encryp_option = opt.clone();
+
+        let task = tokio::spawn(async move {
+            return async_decrypt_stream(&mut ifile, &mut input, &opt).await;
+        });
+
+        return async_decrypt_reader {
+            output,
+            task: Some(task),
+        };
+    }
+}
+
+impl AsyncRead for async_decrypt_reader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let filled: usize = buf.filled().len();
+
+        match Pin::new(&mut this.output).poll_read(cx, buf) {
+            Poll::Pending => return Poll::Pending,
+            Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+            Poll::Ready(Ok(())) => {}
+        }
+
+        if buf.filled().len() > filled || buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        // end of the plaintext, only valid if the decryption succeeded
+        return poll_task(&mut this.task, cx);
+    }
+}
@@ -0,0 +1,48 @@
+use std::io::prelude::*;
+
+use crate::{
+    cipher_algorithm, data_block_type, encryp_option, exmaine_password, file_head, get_kdf,
+    get_small_block, kdf_algorithm, load_key, parse_encrypted_file,
+};
+
+/// What can be learned about an encrypted file without its password.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone)]
+pub struct audit_info {
+    pub salt_a: Vec<u8>,
+    /// Files sharing salt B and the password share the keystream.
+    pub salt_b: Vec<u8>,
+    pub cipher: cipher_algorithm,
+    /// `None` without a kdf block, the password is then hashed as is.
+    pub kdf: Option<kdf_algorithm>,
+    /// The password is empty.
+    pub empty_password: bool,
+    /// The file head is the one neko writes. Only its first 5 bytes are
+    /// checked when decrypting.
+    pub standard_head: bool,
+}
+
+/// Reads the header of an encrypted file, up to its ciphertext.
+///
+/// Finding out whether the password is empty derives a key from it, which
+/// takes as long as unlocking the file.
+pub fn audit_stream<R: Read>(ifile: &mut R) -> Result<audit_info, String> {
+    let mut head = [0_u8; 16];
+    if ifile.read_exact(&mut head).is_err() {
+        return Err(String::from("Failed to read file head."));
+    }
+
+    let efile = parse_encrypted_file(&mut head.as_slice().chain(ifile))?;
+
+    let opt = load_key(&efile, &encryp_option::create(false, false, "", 4096))?;
+    let password_hash = get_small_block(&efile, data_block_type::hash_password)?;
+
+    return Ok(audit_info {
+        empty_password: exmaine_password(&opt, &password_hash),
+        salt_a: opt.salt_a,
+        salt_b: opt.salt_b,
+        cipher: opt.cipher,
+        kdf: get_kdf(&efile)?,
+        standard_head: head == file_head,
+    });
+}
@@ -0,0 +1,199 @@
+use rand::{CryptoRng, RngCore};
+
+use crate::{
+    cancellation_token, cipher_algorithm, compression_option, encryp_option, kdf_algorithm,
+    padding_policy, progress_hook, random_salts,
+};
+
+/// Builds an `encryp_option` and validates it as a whole.
+///
+/// Every setting has the default of `encryp_option::create`, with a buffer
+/// of 64 KiB and existing files kept, except that files are authenticated. `build` refuses values the file format
+/// can't handle instead of failing in the middle of a file.
+///
+/// A builder is a template: unless they are set by `salts` or `rng`, every
+/// option it builds gets salts of its own, so build one per file.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone)]
+pub struct encryp_option_builder {
+    opt: encryp_option,
+    fixed_salts: bool,
+}
+
+impl encryp_option_builder {
+    pub fn new(password: &str) -> encryp_option_builder {
+        let mut opt = encryp_option::create(false, false, password, 65536);
+        opt.authenticated = true;
+        return encryp_option_builder {
+            opt,
+            fixed_salts: false,
+        };
+    }
+
+    /// Whether to keep the source after encrypting or decrypting it.
+    pub fn keep(mut self, keep: bool) -> encryp_option_builder {
+        self.opt.keep = keep;
+        return self;
+    }
+
+    /// Whether to overwrite an existing destination.
+    pub fn cover_existing_file(mut self, cover: bool) -> encryp_option_builder {
+        self.opt.cover_existing_file = cover;
+        return self;
+    }
+
+    /// Must not be 0, and a multiple of 8 in segmented files.
+    pub fn buffer_size(mut self, buffer_size: usize) -> encryp_option_builder {
+        self.opt.buffer_size = buffer_size;
+        return self;
+    }
+
+    /// Uses the given salts instead of random ones, e.g. for reproducible
+    /// output. Never reuse salts for different files.
+    pub fn salts(mut self, salt_a: &[u8], salt_b: &[u8]) -> encryp_option_builder {
+        self.opt.salt_a = salt_a.to_vec();
+        self.opt.salt_b = salt_b.to_vec();
+        self.fixed_salts = true;
+        return self;
+    }
+
+    /// Draws the salts from `rng` instead of the thread local one, once for
+    /// all options built.
+    pub fn rng<R: RngCore + CryptoRng>(mut self, rng: &mut R) -> encryp_option_builder {
+        (self.opt.salt_a, self.opt.salt_b) = random_salts(rng);
+        self.fixed_salts = true;
+        return self;
+    }
+
+    /// Lets options built encrypt more than once with the same salts, which
+    /// is refused otherwise. Only for test vectors, it reuses the keystream.
+    pub fn allow_salt_reuse(mut self, allow: bool) -> encryp_option_builder {
+        self.opt.allow_salt_reuse = allow;
+        return self;
+    }
+
+    /// Draws the salts from a generator seeded with `seed`, so the same
+    /// input always gives the same file. For test vectors only, never enable
+    /// the `fixed-seed` feature in a release.
+    #[cfg(any(test, feature = "fixed-seed"))]
+    pub fn fixed_seed(self, seed: u64) -> encryp_option_builder {
+        use rand::SeedableRng;
+        return self.rng(&mut rand::rngs::StdRng::seed_from_u64(seed));
+    }
+
+    pub fn cipher(mut self, cipher: cipher_algorithm) -> encryp_option_builder {
+        self.opt.cipher = cipher;
+        return self;
+    }
+
+    pub fn kdf(mut self, kdf: kdf_algorithm) -> encryp_option_builder {
+        self.opt.kdf = kdf;
+        return self;
+    }
+
+    /// On by default. Turning it off writes the legacy format, which older
+    /// versions can read, and needs the legacy kdf.
+    pub fn authenticated(mut self, authenticated: bool) -> encryp_option_builder {
+        self.opt.authenticated = authenticated;
+        return self;
+    }
+
+    pub fn compression(mut self, compression: compression_option) -> encryp_option_builder {
+        self.opt.compression = compression;
+        return self;
+    }
+
+    pub fn padding(mut self, padding: padding_policy) -> encryp_option_builder {
+        self.opt.padding = padding;
+        return self;
+    }
+
+    pub fn segment_size(mut self, segment_size: u64) -> encryp_option_builder {
+        self.opt.segment_size = segment_size;
+        return self;
+    }
+
+    pub fn threads(mut self, threads: usize) -> encryp_option_builder {
+        self.opt.threads = threads;
+        return self;
+    }
+
+    pub fn progress(mut self, progress: progress_hook) -> encryp_option_builder {
+        self.opt.progress = progress;
+        return self;
+    }
+
+    pub fn cancel(mut self, cancel: cancellation_token) -> encryp_option_builder {
+        self.opt.cancel = cancel;
+        return self;
+    }
+
+    pub fn build(&self) -> Result<encryp_option, String> {
+        let mut opt: encryp_option = self.opt.clone();
+        if !self.fixed_salts {
+            (opt.salt_a, opt.salt_b) = random_salts(&mut rand::thread_rng());
+        }
+
+        opt.validate()?;
+
+        return Ok(opt);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_seed_is_reproducible() {
+        let a = encryp_option_builder::new("neko")
+            .fixed_seed(7)
+            .build()
+            .unwrap();
+        let b = encryp_option_builder::new("neko")
+            .fixed_seed(7)
+            .build()
+            .unwrap();
+        let c = encryp_option_builder::new("neko")
+            .fixed_seed(8)
+            .build()
+            .unwrap();
+
+        assert_eq!((&a.salt_a, &a.salt_b), (&b.salt_a, &b.salt_b));
+        assert_ne!(a.salt_b, c.salt_b);
+        assert_ne!(a.salt_a, a.salt_b);
+    }
+
+    #[test]
+    fn every_build_has_its_own_salts() {
+        let builder = encryp_option_builder::new("neko");
+        let a = builder.build().unwrap();
+        let b = builder.build().unwrap();
+        assert_ne!((&a.salt_a, &a.salt_b), (&b.salt_a, &b.salt_b));
+
+        let builder = builder.salts(&[1; 16], &[2; 16]);
+        assert_eq!(
+            builder.build().unwrap().salt_b,
+            builder.build().unwrap().salt_b
+        );
+    }
+
+    #[test]
+    fn build_rejects_invalid_options() {
+        assert!(encryp_option_builder::new("")
+            .buffer_size(0)
+            .build()
+            .is_err());
+        assert!(encryp_option_builder::new("")
+            .buffer_size(12)
+            .segment_size(64)
+            .build()
+            .is_err());
+        assert!(encryp_option_builder::new("")
+            .salts(&[0; 8], &[0; 16])
+            .build()
+            .is_err());
+        assert!(encryp_option_builder::new("").threads(0).build().is_err());
+        assert!(encryp_option_builder::new("").build().is_ok());
+    }
+}
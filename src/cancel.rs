@@ -0,0 +1,34 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+/// Shared flag to abort an operation from another thread. Clones refer to
+/// the same flag.
+///
+/// The library checks it every time it reads from the source, so an
+/// operation stops within one buffer after `cancel` is called.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Default)]
+pub struct cancellation_token {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl cancellation_token {
+    pub fn new() -> cancellation_token {
+        return cancellation_token::default();
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        return self.cancelled.load(Ordering::Relaxed);
+    }
+
+    pub(crate) fn check(&self) -> std::io::Result<()> {
+        if self.is_cancelled() {
+            return Err(std::io::Error::other("Cancelled."));
+        }
+        return Ok(());
+    }
+}
@@ -0,0 +1,497 @@
+use std::cmp::min;
+use std::num::Wrapping;
+
+use ::cipher::consts::{U64, U8};
+use ::cipher::inout::InOutBuf;
+use ::cipher::{Iv, IvSizeUser, Key, KeyIvInit, KeySizeUser, StreamCipher, StreamCipherError};
+
+use crate::cipher_algorithm;
+
+/// A cipher that encrypts and decrypts by XORing a keystream into the data.
+#[allow(non_camel_case_types)]
+pub trait keystream_cipher {
+    /// Starts the keystream from `key`, a hash of the key material.
+    fn from_key(key: &[u8]) -> Self
+    where
+        Self: Sized;
+
+    /// XORs the next `data.len()` bytes of the keystream into `data`. Any
+    /// length is accepted, the next call continues where this one stopped.
+    fn apply_keystream(&mut self, data: &mut [u8]);
+
+    /// Bytes the keystream is generated in. Pieces of the ciphertext drop
+    /// the unused rest of their last block, see `keystream::apply`.
+    fn block_size(&self) -> usize {
+        return 1;
+    }
+
+    /// Moves to byte `position` of the keystream, for ciphers with random
+    /// access.
+    fn seek(&mut self, position: u64) -> Result<(), String> {
+        return Err(format!("Can not seek to {} in this keystream.", position));
+    }
+}
+
+impl cipher_algorithm {
+    /// Keystream of this cipher, started from `key`.
+    pub(crate) fn keystream(self, key: &[u8]) -> Box<dyn keystream_cipher + Send> {
+        match self {
+            cipher_algorithm::tent_chaos => return Box::new(tent_chaos::from_key(key)),
+            cipher_algorithm::tent_chaos_v2 => return Box::new(tent_chaos_v2::from_key(key)),
+        }
+    }
+
+    /// Content of the `cipher` block: the id as a little-endian u64.
+    pub(crate) fn encode_block(&self) -> Vec<u8> {
+        return (*self as u64).to_le_bytes().to_vec();
+    }
+
+    pub(crate) fn decode_block(data: &[u8]) -> Result<cipher_algorithm, String> {
+        let id: [u8; 8] = match data.try_into() {
+            Ok(id) => id,
+            Err(_) => return Err(format!("Invalid cipher block of {} bytes.", data.len())),
+        };
+
+        match u64::from_le_bytes(id) {
+            0 => return Ok(cipher_algorithm::tent_chaos),
+            1 => return Ok(cipher_algorithm::tent_chaos_v2),
+            id => return Err(format!("Unknown cipher {}.", id)),
+        }
+    }
+}
+
+/// Keystream of a chaotic tent map, generated one u64 word at a time and
+/// applied as its little-endian bytes on every host.
+#[allow(non_camel_case_types)]
+pub struct tent_chaos {
+    value: Wrapping<u64>,
+    iterate_times: u64,
+    /// Last word of the keystream and how many of its bytes have been used.
+    word: [u8; 8],
+    word_used: usize,
+}
+
+impl tent_chaos {
+    pub fn new(value: u64) -> tent_chaos {
+        let ret = tent_chaos {
+            value: Wrapping(value),
+            iterate_times: 0,
+            word: [0; 8],
+            word_used: 8,
+        };
+
+        return ret;
+    }
+
+    #[allow(clippy::precedence)]
+    pub fn iterate(&mut self) -> u64 {
+        let k = Wrapping(self.iterate_times << 2);
+        let g = self.value + k;
+
+        let seperator = Wrapping(1_u64 << 63);
+
+        if g < seperator {
+            self.value = g << 1 + 1;
+        } else {
+            self.value = (Wrapping(!(0_u64)) - g) << 1;
+        }
+
+        self.iterate_times += 1;
+
+        return self.value.0;
+    }
+
+    /// Fills `data` with the next `data.len()` values, as `iterate` would.
+    #[allow(clippy::precedence)]
+    fn iterate_many_private(&mut self, data: &mut [u64]) {
+        let mut k = Wrapping(self.iterate_times << 2);
+        let mut x = self.value;
+
+        let seperator = Wrapping(1_u64 << 63);
+        for value in data.iter_mut() {
+            let g = x + k;
+            if g < seperator {
+                x = g << 1 + 1;
+            } else {
+                x = (Wrapping(!(0_u64)) - g) << 1;
+            }
+
+            *value = x.0;
+            k += 4;
+        }
+
+        self.value = x;
+        self.iterate_times += data.len() as u64;
+    }
+
+    pub fn iterate_vec(&mut self, times: u64, vec: &mut [u64]) -> Result<(), String> {
+        if vec.len() != times as usize {
+            return Err(String::from("Size mismatch."));
+        }
+
+        self.iterate_many_private(vec);
+
+        return Ok(());
+    }
+
+    pub fn encrypt(&mut self, vec: &mut [u8]) -> Result<(), String> {
+        if !vec.len().is_multiple_of(8) {
+            let mut err_msg =
+                String::from("Length of u8 array should be multiples of 8, but actually it is ");
+
+            err_msg.push_str(vec.len().to_string().as_str());
+            return Err(err_msg);
+        }
+
+        keystream_cipher::apply_keystream(self, vec);
+
+        return Ok(());
+    }
+
+    pub fn iterate_no_ret(&mut self, times: u64) {
+        let mut i = 0u64;
+        loop {
+            if i >= times {
+                break;
+            }
+            self.iterate();
+            i += 1;
+        }
+    }
+}
+
+/// Generators of u64 keystream words, applied by `apply_words`.
+#[allow(non_camel_case_types)]
+trait word_generator {
+    fn next_word(&mut self) -> u64;
+
+    /// Last word of the keystream and how many of its bytes have been used.
+    fn last_word(&mut self) -> (&mut [u8; 8], &mut usize);
+}
+
+/// XORs the little-endian bytes of the words of `generator` into `data`,
+/// continuing with the rest of the last word.
+fn apply_words<G: word_generator>(generator: &mut G, data: &mut [u8]) {
+    // the rest of the word started by the last call
+    let (word, word_used) = generator.last_word();
+    let rest: usize = min(8 - *word_used, data.len());
+    for (byte, key) in data[0..rest].iter_mut().zip(&word[*word_used..]) {
+        *byte ^= key;
+    }
+    *word_used += rest;
+
+    let mut words = data[rest..].chunks_exact_mut(8);
+    for chunk in &mut words {
+        let word = generator.next_word().to_le_bytes();
+        for (byte, key) in chunk.iter_mut().zip(word) {
+            *byte ^= key;
+        }
+    }
+
+    let tail = words.into_remainder();
+    if !tail.is_empty() {
+        let next = generator.next_word().to_le_bytes();
+        for (byte, key) in tail.iter_mut().zip(next) {
+            *byte ^= key;
+        }
+
+        let (word, word_used) = generator.last_word();
+        *word = next;
+        *word_used = tail.len();
+    }
+}
+
+/// XOR of the little-endian words of `key`, padded with zeros.
+fn fold_key(key: &[u8]) -> u64 {
+    let mut value: u64 = 0;
+    for chunk in key.chunks(8) {
+        let mut word = [0_u8; 8];
+        word[0..chunk.len()].copy_from_slice(chunk);
+        value ^= u64::from_le_bytes(word);
+    }
+    return value;
+}
+
+impl word_generator for tent_chaos {
+    fn next_word(&mut self) -> u64 {
+        return self.iterate();
+    }
+
+    fn last_word(&mut self) -> (&mut [u8; 8], &mut usize) {
+        return (&mut self.word, &mut self.word_used);
+    }
+}
+
+impl keystream_cipher for tent_chaos {
+    /// The initial value is the XOR of the little-endian words of `key`.
+    fn from_key(key: &[u8]) -> tent_chaos {
+        return tent_chaos::new(fold_key(key));
+    }
+
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        apply_words(self, data);
+    }
+
+    fn block_size(&self) -> usize {
+        return 8;
+    }
+}
+
+/// The tent map `tent_chaos` was meant to be. There `g << 1 + 1` parses as
+/// `g << 2`, which drops two bits per iteration; this version computes
+/// `(g << 1) + 1`. Files record which of the two they use.
+#[allow(non_camel_case_types)]
+pub struct tent_chaos_v2 {
+    value: Wrapping<u64>,
+    iterate_times: u64,
+    word: [u8; 8],
+    word_used: usize,
+}
+
+impl tent_chaos_v2 {
+    pub fn new(value: u64) -> tent_chaos_v2 {
+        return tent_chaos_v2 {
+            value: Wrapping(value),
+            iterate_times: 0,
+            word: [0; 8],
+            word_used: 8,
+        };
+    }
+
+    pub fn iterate(&mut self) -> u64 {
+        let k = Wrapping(self.iterate_times << 2);
+        let g = self.value + k;
+
+        if g < Wrapping(1_u64 << 63) {
+            self.value = (g << 1) + Wrapping(1);
+        } else {
+            self.value = (Wrapping(!0_u64) - g) << 1;
+        }
+
+        self.iterate_times += 1;
+
+        return self.value.0;
+    }
+}
+
+impl word_generator for tent_chaos_v2 {
+    fn next_word(&mut self) -> u64 {
+        return self.iterate();
+    }
+
+    fn last_word(&mut self) -> (&mut [u8; 8], &mut usize) {
+        return (&mut self.word, &mut self.word_used);
+    }
+}
+
+impl keystream_cipher for tent_chaos_v2 {
+    /// Folds `key` like `tent_chaos`.
+    fn from_key(key: &[u8]) -> tent_chaos_v2 {
+        return tent_chaos_v2::new(fold_key(key));
+    }
+
+    /// Unlike `tent_chaos`, no file depends on dropping the rest of a word at
+    /// the end of a piece, so the keystream is used continuously.
+    fn apply_keystream(&mut self, data: &mut [u8]) {
+        apply_words(self, data);
+    }
+}
+
+impl KeySizeUser for tent_chaos {
+    type KeySize = U64;
+}
+
+impl IvSizeUser for tent_chaos {
+    type IvSize = U8;
+}
+
+/// The key is folded as by `keystream_cipher::from_key`, the IV is XORed
+/// into the initial value. A zero IV gives the keystream of the file format.
+impl KeyIvInit for tent_chaos {
+    fn new(key: &Key<Self>, iv: &Iv<Self>) -> tent_chaos {
+        let mut tent = <tent_chaos as keystream_cipher>::from_key(key);
+        tent.value ^= u64::from_le_bytes((*iv).into());
+        return tent;
+    }
+}
+
+impl StreamCipher for tent_chaos {
+    fn try_apply_keystream_inout(
+        &mut self,
+        mut buf: InOutBuf<'_, '_, u8>,
+    ) -> Result<(), StreamCipherError> {
+        // the keystream is generated a few words at a time
+        let mut keys = [0_u8; 64];
+        while !buf.is_empty() {
+            let len: usize = min(keys.len(), buf.len());
+            let (mut head, tail) = buf.split_at(len);
+
+            keys.fill(0);
+            keystream_cipher::apply_keystream(self, &mut keys[0..len]);
+            head.xor_in2out(&keys[0..len]);
+
+            buf = tail;
+        }
+        return Ok(());
+    }
+}
+
+/// For statistical tests of the generator. It is not a `CryptoRng`: the
+/// output of the tent map is far from uniform, e.g. its low bits are zero.
+impl rand_core::RngCore for tent_chaos {
+    /// The upper half of the next word.
+    fn next_u32(&mut self) -> u32 {
+        return (self.iterate() >> 32) as u32;
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        return self.iterate();
+    }
+
+    /// Fills `dest` with whole words, the rest of the last one is dropped.
+    /// Independent of the partly used word of `apply_keystream`.
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        let mut words: Vec<u64> = vec![0; dest.len().div_ceil(8)];
+        self.iterate_many_private(&mut words);
+
+        for (chunk, word) in dest.chunks_mut(8).zip(words) {
+            chunk.copy_from_slice(&word.to_le_bytes()[0..chunk.len()]);
+        }
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.fill_bytes(dest);
+        return Ok(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::RngCore;
+
+    #[test]
+    fn tail_bytes_continue_the_keystream() {
+        let mut whole = vec![0_u8; 100];
+        keystream_cipher::apply_keystream(&mut tent_chaos::from_key(b"neko"), &mut whole);
+
+        let mut pieces = vec![0_u8; 100];
+        let mut tent = tent_chaos::from_key(b"neko");
+        for piece in pieces.chunks_mut(7) {
+            keystream_cipher::apply_keystream(&mut tent, piece);
+        }
+        assert_eq!(pieces, whole);
+
+        let mut words = vec![0_u8; 104];
+        tent_chaos::from_key(b"neko").encrypt(&mut words).unwrap();
+        assert_eq!(&words[0..100], whole.as_slice());
+        assert!(tent_chaos::from_key(b"neko")
+            .encrypt(&mut words[0..100])
+            .is_err());
+    }
+
+    #[test]
+    fn rust_crypto_traits_match_the_file_format() {
+        let key = [7_u8; 64];
+
+        let mut expected = vec![0_u8; 100];
+        keystream_cipher::apply_keystream(&mut tent_chaos::from_key(&key), &mut expected);
+
+        let mut data = vec![0_u8; 100];
+        let mut tent = <tent_chaos as KeyIvInit>::new(&key.into(), &[0; 8].into());
+        StreamCipher::apply_keystream(&mut tent, &mut data[0..33]);
+        StreamCipher::apply_keystream(&mut tent, &mut data[33..]);
+        assert_eq!(data, expected);
+
+        // longer than the scratch buffer of the keystream
+        let mut long = vec![0_u8; 1000];
+        keystream_cipher::apply_keystream(&mut tent_chaos::from_key(&key), &mut long);
+        let mut data = vec![0_u8; 1000];
+        let mut tent = <tent_chaos as KeyIvInit>::new(&key.into(), &[0; 8].into());
+        StreamCipher::apply_keystream(&mut tent, &mut data[0..5]);
+        StreamCipher::apply_keystream(&mut tent, &mut data[5..]);
+        assert_eq!(data, long);
+
+        let mut other_iv = vec![0_u8; 100];
+        let mut tent = <tent_chaos as KeyIvInit>::new(&key.into(), &[1; 8].into());
+        StreamCipher::apply_keystream(&mut tent, &mut other_iv);
+        assert_ne!(other_iv, expected);
+    }
+
+    #[test]
+    fn rng_follows_the_sequence() {
+        let mut sequence = tent_chaos::new(42);
+        let words: Vec<u64> = (0..4).map(|_| sequence.iterate()).collect();
+
+        let mut rng = tent_chaos::new(42);
+        assert_eq!(rng.next_u64(), words[0]);
+        assert_eq!(rng.next_u32(), (words[1] >> 32) as u32);
+
+        let mut bytes = [0_u8; 12];
+        rng.fill_bytes(&mut bytes);
+        assert_eq!(&bytes[0..8], &words[2].to_le_bytes());
+        assert_eq!(&bytes[8..12], &words[3].to_le_bytes()[0..4]);
+
+        let mut vector = [0_u64; 4];
+        tent_chaos::new(42).iterate_vec(4, &mut vector).unwrap();
+        assert_eq!(vector.as_slice(), words.as_slice());
+    }
+
+    #[test]
+    fn tent_v2_is_the_tent_map() {
+        let mut tent = tent_chaos_v2::new(42);
+        assert_eq!(tent.iterate(), 85);
+        // the values double until they reach the falling half of the map
+        let mut value: u64 = 85;
+        for times in 1..100_u64 {
+            let g = value.wrapping_add(times << 2);
+            value = if g < 1 << 63 {
+                (g << 1) + 1
+            } else {
+                (u64::MAX - g) << 1
+            };
+            assert_eq!(tent.iterate(), value);
+        }
+
+        let mut legacy = tent_chaos::new(42);
+        assert_eq!(legacy.iterate(), 42 << 2);
+
+        let mut whole = vec![0_u8; 100];
+        keystream_cipher::apply_keystream(&mut tent_chaos_v2::from_key(b"neko"), &mut whole);
+
+        let mut pieces = vec![0_u8; 100];
+        let mut tent = tent_chaos_v2::from_key(b"neko");
+        for piece in pieces.chunks_mut(7) {
+            keystream_cipher::apply_keystream(&mut tent, piece);
+        }
+        assert_eq!(pieces, whole);
+
+        let mut legacy = vec![0_u8; 100];
+        keystream_cipher::apply_keystream(&mut tent_chaos::from_key(b"neko"), &mut legacy);
+        assert_ne!(legacy, whole);
+    }
+
+    #[test]
+    fn only_tent_chaos_drops_the_rest_of_a_word() {
+        for cipher in [
+            cipher_algorithm::tent_chaos,
+            cipher_algorithm::tent_chaos_v2,
+        ] {
+            let opt = crate::encryp_option_builder::new("neko")
+                .cipher(cipher)
+                .build()
+                .unwrap();
+
+            let mut whole = vec![0_u8; 100];
+            crate::keystream::single(&opt, b"").apply(&mut whole);
+
+            let mut pieces = vec![0_u8; 100];
+            let mut keys = crate::keystream::single(&opt, b"");
+            for piece in pieces.chunks_mut(7) {
+                keys.apply(piece);
+            }
+
+            assert_eq!(pieces == whole, cipher == cipher_algorithm::tent_chaos_v2);
+        }
+    }
+}
@@ -0,0 +1,177 @@
+use std::io::prelude::*;
+
+/// Compression applied to the plaintext before it is encrypted.
+#[repr(u64)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum compression_algorithm {
+    none = 0,
+    zstd = 1,
+    lz4 = 2,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct compression_option {
+    pub algorithm: compression_algorithm,
+    /// Only used by zstd, lz4 has no levels.
+    pub level: i32,
+    /// Compress a sample of every file first and store the file uncompressed
+    /// if it does not shrink, e.g. for media or archives.
+    pub auto: bool,
+}
+
+impl compression_option {
+    pub fn none() -> compression_option {
+        return compression_option {
+            algorithm: compression_algorithm::none,
+            level: 0,
+            auto: false,
+        };
+    }
+
+    /// Picks the algorithm for a file starting with `sample`.
+    pub(crate) fn choose(&self, sample: &[u8]) -> compression_algorithm {
+        if !self.auto || self.algorithm == compression_algorithm::none {
+            return self.algorithm;
+        }
+
+        if sample.is_empty() {
+            return compression_algorithm::none;
+        }
+
+        // the fastest zstd level is a good enough estimate for every algorithm
+        let compressed = match zstd::bulk::compress(sample, 1) {
+            Ok(v) => v,
+            Err(_) => return compression_algorithm::none,
+        };
+
+        if compressed.len() * 100 >= sample.len() * auto_threshold_percent {
+            return compression_algorithm::none;
+        }
+
+        return self.algorithm;
+    }
+}
+
+/// Samples compressed to more than this share of their size are stored as is.
+#[allow(non_upper_case_globals)]
+const auto_threshold_percent: usize = 95;
+
+/// Content of the `compression` data block: the algorithm followed by the
+/// level, both as little-endian 64-bit integers.
+pub(crate) fn encode_block(algorithm: compression_algorithm, level: i32) -> Vec<u8> {
+    let mut ret: Vec<u8> = Vec::with_capacity(16);
+    ret.extend_from_slice((algorithm as u64).to_le_bytes().as_slice());
+    ret.extend_from_slice((level as i64).to_le_bytes().as_slice());
+    return ret;
+}
+
+pub(crate) fn decode_block(data: &[u8]) -> Result<compression_algorithm, String> {
+    if data.len() != 16 {
+        return Err(format!(
+            "Invalid compression block of {} bytes.",
+            data.len()
+        ));
+    }
+
+    let mut algorithm = [0_u8; 8];
+    algorithm.copy_from_slice(&data[0..8]);
+
+    match u64::from_le_bytes(algorithm) {
+        0 => return Ok(compression_algorithm::none),
+        1 => return Ok(compression_algorithm::zstd),
+        2 => return Ok(compression_algorithm::lz4),
+        other => return Err(format!("Unknown compression algorithm {}.", other)),
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub(crate) enum compressor<W: Write> {
+    none(W),
+    zstd(zstd::stream::write::Encoder<'static, W>),
+    lz4(lz4_flex::frame::FrameEncoder<W>),
+}
+
+impl<W: Write> compressor<W> {
+    pub(crate) fn new(
+        algorithm: compression_algorithm,
+        level: i32,
+        ofile: W,
+    ) -> std::io::Result<compressor<W>> {
+        match algorithm {
+            compression_algorithm::none => return Ok(compressor::none(ofile)),
+            compression_algorithm::zstd => {
+                return Ok(compressor::zstd(zstd::stream::write::Encoder::new(
+                    ofile, level,
+                )?));
+            }
+            compression_algorithm::lz4 => {
+                return Ok(compressor::lz4(lz4_flex::frame::FrameEncoder::new(ofile)));
+            }
+        }
+    }
+
+    /// Writes the end of the compressed stream and returns the inner writer.
+    pub(crate) fn finish(self) -> std::io::Result<W> {
+        match self {
+            compressor::none(ofile) => return Ok(ofile),
+            compressor::zstd(encoder) => return encoder.finish(),
+            compressor::lz4(encoder) => {
+                return encoder.finish().map_err(std::io::Error::other);
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for compressor<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            compressor::none(ofile) => return ofile.write(buf),
+            compressor::zstd(encoder) => return encoder.write(buf),
+            compressor::lz4(encoder) => return encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            compressor::none(ofile) => return ofile.flush(),
+            compressor::zstd(encoder) => return encoder.flush(),
+            compressor::lz4(encoder) => return encoder.flush(),
+        }
+    }
+}
+
+#[allow(non_camel_case_types)]
+pub(crate) enum decompressor<R: Read> {
+    none(R),
+    zstd(zstd::stream::read::Decoder<'static, std::io::BufReader<R>>),
+    lz4(lz4_flex::frame::FrameDecoder<R>),
+}
+
+impl<R: Read> decompressor<R> {
+    pub(crate) fn new(
+        algorithm: compression_algorithm,
+        ifile: R,
+    ) -> std::io::Result<decompressor<R>> {
+        match algorithm {
+            compression_algorithm::none => return Ok(decompressor::none(ifile)),
+            compression_algorithm::zstd => {
+                return Ok(decompressor::zstd(zstd::stream::read::Decoder::new(ifile)?));
+            }
+            compression_algorithm::lz4 => {
+                return Ok(decompressor::lz4(lz4_flex::frame::FrameDecoder::new(ifile)));
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for decompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            decompressor::none(ifile) => return ifile.read(buf),
+            decompressor::zstd(decoder) => return decoder.read(buf),
+            decompressor::lz4(decoder) => return decoder.read(buf),
+        }
+    }
+}
@@ -1,3 +1,7 @@
+#![allow(clippy::needless_return)]
+#![forbid(unsafe_code)]
+
+use std::cmp::min;
 use std::fs;
 //use std::io;
 use std::io::prelude::*;
@@ -5,9 +9,68 @@ use std::io::prelude::*;
 use rand::Rng;
 use sha3::Digest;
 
+mod compress;
+pub use compress::{compression_algorithm, compression_option};
+
+mod padding;
+pub use padding::padding_policy;
+
+mod kdf;
+pub use kdf::kdf_algorithm;
+
+mod mac;
+
+mod cipher;
+pub use cipher::{keystream_cipher, tent_chaos, tent_chaos_v2};
+
+mod builder;
+pub use builder::encryp_option_builder;
+
+mod archive;
+pub use archive::{
+    archive_entry, archive_entry_kind, list_archive, pack_archive, pack_stream, unpack_archive,
+};
+
+mod seekable;
+pub use seekable::{decrypt_range, decrypt_reader};
+
+mod parallel;
+pub use parallel::{decrypt_parallel, encryp_parallel};
+
+mod progress;
+pub use progress::{progress_callback, progress_hook, progress_info};
+
+mod cancel;
+pub use cancel::cancellation_token;
+
+mod audit;
+pub use audit::{audit_info, audit_stream};
+
+mod upgrade;
+pub use upgrade::{try_upgrade_file, upgrade_stream};
+
+#[cfg(feature = "async")]
+mod async_io;
+#[cfg(feature = "async")]
+pub use async_io::{
+    async_decrypt_file, async_decrypt_reader, async_decrypt_stream, async_encryp_file,
+    async_encryp_stream, async_encryp_writer,
+};
+
 #[allow(non_upper_case_globals)]
 pub const suffix: &str = ".neko";
 
+/// Cipher generating the keystream.
+#[repr(u64)]
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum cipher_algorithm {
+    /// The map of the first versions, kept bit-exact for their files.
+    tent_chaos = 0,
+    /// The tent map as intended.
+    tent_chaos_v2 = 1,
+}
+
 #[allow(non_camel_case_types)]
 #[derive(Debug, Clone)]
 pub struct encryp_option {
@@ -17,45 +80,299 @@ pub struct encryp_option {
     pub password: String,
     pub salt_a: Vec<u8>,
     pub salt_b: Vec<u8>,
+    pub compression: compression_option,
+    pub padding: padding_policy,
+    /// Size of the independently encrypted segments, 0 for a single
+    /// keystream. Segmented files can be decrypted at random offsets.
+    pub segment_size: u64,
+    /// Number of threads used to encrypt or decrypt a single file, see
+    /// `encryp_parallel`.
+    pub threads: usize,
+    /// Called as the source is read.
+    pub progress: progress_hook,
+    /// Checked as the source is read, see `encryp_error::cancelled`.
+    pub cancel: cancellation_token,
+    pub cipher: cipher_algorithm,
+    pub kdf: kdf_algorithm,
+    /// Protects the file with a MAC and a header tag keyed from the password,
+    /// instead of the unkeyed SHA3-512 of the plaintext. Required by a kdf,
+    /// older versions can only read files without it. Off in `create`, on in
+    /// `encryp_option_builder`.
+    pub authenticated: bool,
+    /// Key derived by `kdf`, set by `derive_key` for a single operation.
+    derived_key: Option<Vec<u8>>,
+    /// Skips `claim_salts`, see `encryp_option_builder::allow_salt_reuse`.
+    allow_salt_reuse: bool,
+}
+
+/// Error of `try_encryp_file` and `try_decrypt_file`.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum encryp_error {
+    /// `encryp_option::cancel` was cancelled. The destination has been
+    /// removed.
+    cancelled,
+    failed(String),
+}
+
+impl std::fmt::Display for encryp_error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            encryp_error::cancelled => return write!(f, "Cancelled."),
+            encryp_error::failed(err) => return write!(f, "{}", err),
+        }
+    }
 }
 
+impl std::error::Error for encryp_error {}
+
+/// Type of a data block. A block is written as its type and the length of
+/// its content, both little-endian u64, followed by the content.
 #[repr(u64)]
 #[allow(non_camel_case_types)]
-#[derive(Eq, Hash, PartialEq, Debug)]
+#[derive(Eq, Hash, PartialEq, Debug, Clone, Copy)]
 pub enum data_block_type {
     salt_a = 42,
     salt_b = 114514,
     hash_password = 1919810,
     ciphertext = 666,
     sha3_512_original_file = 2300,
+    /// Ciphertext of unknown length, written as length-prefixed chunks.
+    ciphertext_stream = 6666,
+    /// Compression applied to the plaintext before encryption.
+    compression = 2333,
+    /// Padding policy, its presence means the payload is framed by `padder`.
+    padding = 2334,
+    /// Encrypted table of contents of an archive.
+    archive_index = 4545,
+    /// Encrypted members of an archive.
+    archive_payload = 4546,
+    /// Segment size of a file encrypted with one keystream per segment.
+    segment_size = 3001,
+    /// SHA3-512 of every plaintext segment, concatenated. HMAC-SHA3-512
+    /// keyed like `mac` in authenticated files.
+    segment_hashes = 3002,
+    /// SHA3-512 of `segment_hashes`, written instead of
+    /// `sha3_512_original_file` when segments are hashed in parallel.
+    sha3_512_tree = 3003,
+    /// Key derivation, the password is used as is without it.
+    kdf = 3101,
+    /// HMAC-SHA3-512 of all bytes in front of it, keyed from the password or
+    /// the derived key. Replaces the unkeyed checksums in authenticated
+    /// files and must be their last block.
+    mac = 3102,
+    /// HMAC-SHA3-512 of the raw file head and of all blocks in front of it,
+    /// including unknown ones. Directly precedes the ciphertext or the
+    /// archive payload of authenticated files, whose header it identifies,
+    /// and is checked before anything is decrypted.
+    header_tag = 3103,
+    /// Cipher of the keystream, `tent_chaos` without it.
+    cipher = 3104,
+}
+
+impl data_block_type {
+    /// `None` for a block type this version does not know.
+    fn from_u64(value: u64) -> Option<data_block_type> {
+        return [
+            data_block_type::salt_a,
+            data_block_type::salt_b,
+            data_block_type::hash_password,
+            data_block_type::ciphertext,
+            data_block_type::sha3_512_original_file,
+            data_block_type::ciphertext_stream,
+            data_block_type::compression,
+            data_block_type::padding,
+            data_block_type::archive_index,
+            data_block_type::archive_payload,
+            data_block_type::segment_size,
+            data_block_type::segment_hashes,
+            data_block_type::sha3_512_tree,
+            data_block_type::kdf,
+            data_block_type::mac,
+            data_block_type::header_tag,
+            data_block_type::cipher,
+        ]
+        .into_iter()
+        .find(|blk_type| *blk_type as u64 == value);
+    }
+
+    /// Large blocks end the header, their payload is not loaded into memory.
+    fn is_large(&self) -> bool {
+        return matches!(
+            self,
+            data_block_type::ciphertext
+                | data_block_type::ciphertext_stream
+                | data_block_type::archive_payload
+        );
+    }
+
+    /// Longest content accepted for a small block, so that a corrupted or
+    /// malicious length can not exhaust the memory.
+    fn max_len(&self) -> u64 {
+        match self {
+            data_block_type::segment_hashes | data_block_type::archive_index => {
+                return max_table_block_len;
+            }
+            _ => return max_small_block_len,
+        }
+    }
 }
 
+/// Salts, hashes and parameters are much shorter.
+#[allow(non_upper_case_globals)]
+const max_small_block_len: u64 = 4096;
+
+/// Enough for a million segments or archive entries.
+#[allow(non_upper_case_globals)]
+const max_table_block_len: u64 = 256 << 20;
+
+/// Segments are held in memory as a whole when decrypted in parallel or
+/// randomly accessed.
+#[allow(non_upper_case_globals)]
+pub(crate) const max_segment_size: u64 = 64 << 20;
+
 impl encryp_option {
+    /// Options for the legacy format, which every release can read. New code
+    /// should use `encryp_option_builder`, which writes authenticated files.
     pub fn create(
         keep: bool,
         cover_existing_file: bool,
-        password: &String,
+        password: &str,
         buffer_size: usize,
     ) -> encryp_option {
-        let mut rng = rand::thread_rng();
-
-        let salt_a: u128 = rng.gen();
-        let salt_a = salt_a.to_le_bytes().to_vec();
+        return encryp_option::create_with_rng(
+            keep,
+            cover_existing_file,
+            password,
+            buffer_size,
+            &mut rand::thread_rng(),
+        );
+    }
 
-        let salt_b: u128 = rng.gen();
-        let salt_b = salt_b.to_le_bytes().to_vec();
+    /// Like `create`, drawing the salts from `rng`. With a seeded `rng` the
+    /// output is reproducible, which is only ever wanted in tests.
+    pub fn create_with_rng<R: rand::RngCore + rand::CryptoRng>(
+        keep: bool,
+        cover_existing_file: bool,
+        password: &str,
+        buffer_size: usize,
+        rng: &mut R,
+    ) -> encryp_option {
+        let (salt_a, salt_b) = random_salts(rng);
 
         let ret = encryp_option {
-            keep: keep,
-            cover_existing_file: cover_existing_file,
-            password: password.clone(),
-            salt_a: salt_a,
-            salt_b: salt_b,
-            buffer_size: buffer_size,
+            keep,
+            cover_existing_file,
+            password: password.to_string(),
+            salt_a,
+            salt_b,
+            buffer_size,
+            compression: compression_option::none(),
+            padding: padding_policy::none,
+            segment_size: 0,
+            threads: 1,
+            progress: progress_hook::none(),
+            cancel: cancellation_token::new(),
+            cipher: cipher_algorithm::tent_chaos,
+            kdf: kdf_algorithm::legacy,
+            authenticated: false,
+            derived_key: None,
+            allow_salt_reuse: false,
         };
 
         return ret;
     }
+
+    /// Secret the keystreams and the password hash are computed from.
+    fn key_material(&self) -> &[u8] {
+        match &self.derived_key {
+            Some(key) => return key,
+            None => return self.password.as_bytes(),
+        }
+    }
+
+    /// Refuses values the file format can't handle instead of failing in the
+    /// middle of a file. Done by `encryp_option_builder::build` and again by
+    /// every operation, as options may also come from `create`.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.buffer_size == 0 {
+            return Err(String::from("Buffer size must not be 0."));
+        }
+
+        if self.salt_a.len() != salt_len || self.salt_b.len() != salt_len {
+            return Err(format!("Salts must be {} bytes long.", salt_len));
+        }
+
+        if self.threads == 0 {
+            return Err(String::from("At least 1 thread is needed."));
+        }
+
+        if self.compression.algorithm == compression_algorithm::zstd
+            && !zstd::compression_level_range().contains(&self.compression.level)
+        {
+            return Err(format!(
+                "Invalid zstd compression level {}.",
+                self.compression.level
+            ));
+        }
+
+        if self.padding == padding_policy::bucket(0) {
+            return Err(String::from("Padding bucket size must not be 0."));
+        }
+
+        if self.segment_size != 0 {
+            check_segmented(self)?;
+        }
+
+        self.kdf.validate()?;
+        if self.kdf != kdf_algorithm::legacy && !self.authenticated {
+            return Err(String::from("Files with a kdf are always authenticated."));
+        }
+
+        return Ok(());
+    }
+}
+
+/// Length of the salts generated for new files.
+#[allow(non_upper_case_globals)]
+const salt_len: usize = 16;
+
+fn random_salts<R: rand::RngCore>(rng: &mut R) -> (Vec<u8>, Vec<u8>) {
+    let salt_a: u128 = rng.gen();
+    let salt_b: u128 = rng.gen();
+    return (salt_a.to_le_bytes().to_vec(), salt_b.to_le_bytes().to_vec());
+}
+
+/// Salts B already used to encrypt in this process, see `claim_salts`.
+#[allow(non_upper_case_globals)]
+static used_salts: std::sync::Mutex<std::collections::BTreeSet<Vec<u8>>> =
+    std::sync::Mutex::new(std::collections::BTreeSet::new());
+
+/// Refuses a salt B that was already used to encrypt in this process. The
+/// keystream only depends on it and the password, whatever salt A is, and
+/// two ciphertexts of the same keystream XOR to the XOR of their plaintexts.
+fn claim_salts(opt: &encryp_option) -> Result<(), String> {
+    if opt.allow_salt_reuse {
+        return Ok(());
+    }
+
+    let mut used = used_salts.lock().unwrap_or_else(|e| e.into_inner());
+    if !used.insert(opt.salt_b.clone()) {
+        return Err(String::from(
+            "Refusing to encrypt with a salt B already used in this session.",
+        ));
+    }
+    return Ok(());
+}
+
+/// Copy of `opt` with the key derived from its password and salt B. Done
+/// once per operation as the KDF is deliberately slow.
+fn derive_key(opt: &encryp_option) -> Result<encryp_option, String> {
+    let mut opt: encryp_option = opt.clone();
+    if opt.derived_key.is_none() {
+        opt.derived_key = opt.kdf.derive(opt.password.as_bytes(), &opt.salt_b)?;
+    }
+    return Ok(opt);
 }
 
 #[allow(non_camel_case_types)]
@@ -87,7 +404,7 @@ fn create_file_stream(
     }
 
     let ifile = fs::File::open(src_name);
-    if !ifile.is_ok() {
+    if ifile.is_err() {
         return Err(String::from("Error : failed to open source file."));
     }
 
@@ -99,7 +416,7 @@ fn create_file_stream(
         .write(true)
         .open(dst_name);
 
-    if !ofile.is_ok() {
+    if ofile.is_err() {
         return Err(String::from("Error : failed to open/create dest file"));
     }
 
@@ -112,258 +429,567 @@ fn create_file_stream(
 }
 
 #[allow(non_upper_case_globals)]
-const file_head: [u8; 16] = [
-    0,
-    0,
-    ('T' as u8),
-    ('e' as u8),
-    ('n' as u8),
-    ('t' as u8),
-    4,
-    0,
-    0,
-    0,
-    0,
-    0,
-    0,
-    0,
-    0,
-    0,
-];
-
-fn write_data_block_head(ofile: &mut fs::File, data_type: data_block_type, len: u64) {
-    ofile
-        .write((data_type as u64).to_le_bytes().as_slice())
-        .unwrap();
-    ofile.write(len.to_le_bytes().as_slice()).unwrap();
-}
-
-fn write_data_block(ofile: &mut fs::File, data_type: data_block_type, data_u8: &[u8]) {
-    write_data_block_head(ofile, data_type, data_u8.len() as u64);
-
-    ofile.write(data_u8).unwrap();
-}
-
-fn compute_initial_x(opt: &encryp_option) -> u64 {
-    let mut x_beg: u64;
-    {
-        let mut hasher = sha3::Sha3_512::new();
-        hasher.update(opt.password.as_bytes());
-        hasher.update(opt.salt_b.as_slice());
+const file_head: [u8; 16] = [0, 0, b'T', b'e', b'n', b't', 4, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+fn write_data_block_head<W: Write>(
+    ofile: &mut W,
+    data_type: data_block_type,
+    len: u64,
+) -> std::io::Result<()> {
+    ofile.write_all((data_type as u64).to_le_bytes().as_slice())?;
+    ofile.write_all(len.to_le_bytes().as_slice())?;
+    return Ok(());
+}
 
-        let hash_ptr: *const u64 = hasher.finalize().as_ptr() as *const u64;
+fn write_data_block<W: Write>(
+    ofile: &mut W,
+    data_type: data_block_type,
+    data_u8: &[u8],
+) -> std::io::Result<()> {
+    if !data_type.is_large() && data_u8.len() as u64 > data_type.max_len() {
+        return Err(std::io::Error::other(format!(
+            "{:?} block of {} bytes is too long.",
+            data_type,
+            data_u8.len()
+        )));
+    }
 
-        unsafe {
-            x_beg = *hash_ptr;
+    write_data_block_head(ofile, data_type, data_u8.len() as u64)?;
 
-            let mut i = 1;
+    ofile.write_all(data_u8)?;
+    return Ok(());
+}
 
-            loop {
-                if i >= 8 {
-                    break;
-                }
+fn write_error(err: std::io::Error) -> String {
+    return format!("Failed to write : {}", err);
+}
 
-                x_beg = x_beg ^ (*hash_ptr.add(i));
+fn read_error(err: std::io::Error) -> String {
+    return format!("Failed to read : {}", err);
+}
 
-                i += 1;
-            }
+/// Reads until `buffer` is full or the end of the stream is reached, so that
+/// short reads from pipes do not end the input early. Returns the bytes read.
+fn read_full<R: Read>(ifile: &mut R, buffer: &mut [u8]) -> std::io::Result<usize> {
+    let mut total: usize = 0;
+    while total < buffer.len() {
+        match ifile.read(&mut buffer[total..]) {
+            Ok(0) => break,
+            Ok(bytes) => total += bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
         }
     }
-
-    return x_beg;
+    return Ok(total);
 }
 
-pub fn encryp_file(src_name: &String, dst_name: &String, opt: &encryp_option) -> bool {
-    let streams = create_file_stream(src_name, dst_name, opt);
-    if streams.is_err() {
-        println!("{:?}", streams.err());
-        return false;
-    }
-
-    let streams = streams.unwrap();
+/// Key of the keystream used for `context` within a file. The ciphertext
+/// block uses an empty context, which keeps legacy files readable.
+fn keystream_key(opt: &encryp_option, context: &[u8]) -> Vec<u8> {
+    let mut hasher = sha3::Sha3_512::new();
+    hasher.update(opt.key_material());
+    hasher.update(opt.salt_b.as_slice());
+    hasher.update(context);
+    return hasher.finalize().to_vec();
+}
 
-    let mut ofile = streams.ofile;
+/// Writes the file head, the salts and the password hash.
+fn write_header<W: Write>(ofile: &mut W, opt: &encryp_option) -> Result<(), String> {
+    claim_salts(opt)?;
 
-    ofile.write(file_head.as_slice()).unwrap();
+    ofile.write_all(file_head.as_slice()).map_err(write_error)?;
     //write salt A
-    write_data_block(&mut ofile, data_block_type::salt_a, &opt.salt_a.as_slice());
+    write_data_block(ofile, data_block_type::salt_a, opt.salt_a.as_slice()).map_err(write_error)?;
 
     //write salt B
-    write_data_block(&mut ofile, data_block_type::salt_b, &opt.salt_b.as_slice());
-    //write hashed password (sha3-512)
-    {
-        let mut hasher_password = sha3::Sha3_512::new();
-        hasher_password.update(opt.password.as_bytes());
-        hasher_password.update(opt.salt_a.as_slice());
-
-        let hash_psw = hasher_password.finalize().to_vec();
+    write_data_block(ofile, data_block_type::salt_b, opt.salt_b.as_slice()).map_err(write_error)?;
 
-        write_data_block(
-            &mut ofile,
-            data_block_type::hash_password,
-            hash_psw.as_slice(),
-        );
+    if opt.cipher != cipher_algorithm::tent_chaos {
+        write_data_block(ofile, data_block_type::cipher, &opt.cipher.encode_block())
+            .map_err(write_error)?;
     }
-    let buffer_size: usize = opt.buffer_size;
 
-    let mut buffer: Vec<u8> = Vec::new();
-    buffer.resize(buffer_size, 0xFF);
-
-    let mut ifile = streams.ifile;
-    {
-        let file_size: u64 = ifile.metadata().unwrap().len();
-        write_data_block_head(&mut ofile, data_block_type::ciphertext, file_size);
+    if opt.kdf != kdf_algorithm::legacy {
+        if opt.derived_key.is_none() {
+            return Err(String::from("Key has not been derived."));
+        }
+        write_data_block(ofile, data_block_type::kdf, &opt.kdf.encode_block())
+            .map_err(write_error)?;
     }
 
-    let x_beg: u64 = compute_initial_x(&opt);
+    //write hashed password (sha3-512)
+    write_data_block(ofile, data_block_type::hash_password, &hash_password(opt))
+        .map_err(write_error)?;
 
-    let mut tent = tent_chaos::new(x_beg);
+    return Ok(());
+}
 
+/// SHA3-512 of the key material and salt A. In authenticated files it also
+/// covers the format, so that dropping the header tag or adding one to a
+/// legacy file fails the password check, before anything is decrypted.
+fn hash_password(opt: &encryp_option) -> Vec<u8> {
     let mut hasher = sha3::Sha3_512::new();
+    hasher.update(opt.key_material());
+    hasher.update(&opt.salt_a);
+    if opt.authenticated {
+        hasher.update(b"neko-encrypt authenticated");
+    }
+    return hasher.finalize().to_vec();
+}
 
-    loop {
-        //loop {
-        let read_bytes = ifile.read(&mut buffer).unwrap();
+fn segment_context(segment: u64) -> Vec<u8> {
+    let mut ret: Vec<u8> = Vec::from(b"segment".as_slice());
+    ret.extend_from_slice(segment.to_le_bytes().as_slice());
+    return ret;
+}
 
-        hasher.update(&buffer[0..read_bytes]);
+/// The keystream of a ciphertext: one cipher for all of it, or, in segmented
+/// files, a fresh one for every segment, so that each segment can be
+/// decrypted on its own.
+#[allow(non_camel_case_types)]
+struct keystream {
+    cipher: Box<dyn keystream_cipher + Send>,
+    /// Option to derive the keystream of the next segment from, and the
+    /// segment size. `None` for a single keystream.
+    segments: Option<(encryp_option, u64)>,
+    segment: u64,
+    segment_left: u64,
+}
 
-        let read_bytes_ceil: usize = {
-            if read_bytes % 8 == 0 {
-                read_bytes
-            } else {
-                (read_bytes | 0b111) + 1
-                //buffer_size
-            }
+impl keystream {
+    fn single(opt: &encryp_option, context: &[u8]) -> keystream {
+        return keystream {
+            cipher: opt.cipher.keystream(&keystream_key(opt, context)),
+            segments: None,
+            segment: 0,
+            segment_left: u64::MAX,
         };
+    }
 
-        //println!("read_bytes_ceil")
+    fn segmented(opt: &encryp_option, segment_size: u64) -> keystream {
+        return keystream::segmented_from(opt, segment_size, 0);
+    }
 
-        tent.encrypt(&mut buffer[0..read_bytes_ceil]).unwrap();
+    /// Keystream of a segmented file, starting at the segment `first`.
+    fn segmented_from(opt: &encryp_option, segment_size: u64, first: u64) -> keystream {
+        return keystream {
+            cipher: opt
+                .cipher
+                .keystream(&keystream_key(opt, &segment_context(first))),
+            segments: Some((opt.clone(), segment_size)),
+            segment: first,
+            segment_left: segment_size,
+        };
+    }
 
-        //println!("{read_bytes} bytes read");
+    /// Bytes left before the keystream switches to the next segment. A piece
+    /// passed to `apply` must not be longer than this.
+    fn segment_left(&self) -> u64 {
+        return self.segment_left;
+    }
 
-        ofile.write(&buffer[0..read_bytes]).unwrap();
-        //}
-        //println!("{wrote_bytes} bytes wrote");
+    /// Applies the keystream to a piece of the ciphertext. As the file used
+    /// to be encrypted in whole blocks, the rest of the last block of the
+    /// piece is not used for the next one.
+    fn apply(&mut self, piece: &mut [u8]) {
+        self.cipher.apply_keystream(piece);
 
-        if read_bytes < buffer_size {
-            break;
+        let block_size: usize = self.cipher.block_size();
+        if !piece.len().is_multiple_of(block_size) {
+            let mut unused: Vec<u8> = vec![0; block_size - piece.len() % block_size];
+            self.cipher.apply_keystream(&mut unused);
         }
-    }
 
-    write_data_block(
-        &mut ofile,
-        data_block_type::sha3_512_original_file,
-        &hasher.finalize().to_vec(),
-    );
+        if let Some((opt, segment_size)) = &self.segments {
+            self.segment_left -= piece.len() as u64;
+            if self.segment_left == 0 {
+                self.segment += 1;
+                self.segment_left = *segment_size;
+                self.cipher = opt
+                    .cipher
+                    .keystream(&keystream_key(opt, &segment_context(self.segment)));
+            }
+        }
+    }
+}
 
-    return true;
+/// Hashes every segment of the plaintext on its own, so that a segment can be
+/// verified without the rest of the file.
+#[allow(non_camel_case_types)]
+struct segment_hasher {
+    segment_size: u64,
+    segment_left: u64,
+    key: Option<Vec<u8>>,
+    hasher: mac::segment_digest,
+    hashes: Vec<u8>,
 }
 
-use std::num::Wrapping;
+impl segment_hasher {
+    /// `key` is the MAC key of an authenticated file.
+    fn new(segment_size: u64, key: Option<Vec<u8>>) -> segment_hasher {
+        return segment_hasher {
+            segment_size,
+            segment_left: segment_size,
+            hasher: mac::segment_digest::new(key.as_deref()),
+            key,
+            hashes: Vec::new(),
+        };
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        while !data.is_empty() {
+            let bytes: usize = min(self.segment_left, data.len() as u64) as usize;
+            self.hasher.update(&data[0..bytes]);
+            self.segment_left -= bytes as u64;
+            data = &data[bytes..];
+
+            if self.segment_left == 0 {
+                let hasher = std::mem::replace(
+                    &mut self.hasher,
+                    mac::segment_digest::new(self.key.as_deref()),
+                );
+                self.hashes.extend_from_slice(&hasher.finalize());
+                self.segment_left = self.segment_size;
+            }
+        }
+    }
+
+    /// The hashes of all segments, concatenated.
+    fn finish(mut self) -> Vec<u8> {
+        if self.segment_left != self.segment_size {
+            self.hashes.extend_from_slice(&self.hasher.finalize());
+        }
+        return self.hashes;
+    }
+}
 
+/// Encrypts everything written to it in pieces of `buffer_size` bytes,
+/// rounded up to a multiple of 8, the same way the file used to be encrypted
+/// buffer by buffer, so that the keystream lines up with the legacy format.
+/// Pieces never cross the border of a segment.
 #[allow(non_camel_case_types)]
-pub struct tent_chaos {
-    value: Wrapping<u64>,
-    iterate_times: u64,
+struct ciphertext_writer<'a, W: Write> {
+    ofile: &'a mut W,
+    keys: keystream,
+    buffer: Vec<u8>,
+    piece_size: usize,
+    filled: usize,
+    /// Prefix every piece with its length, as in `ciphertext_stream` blocks.
+    chunked: bool,
 }
 
-impl tent_chaos {
-    pub fn new(value: u64) -> tent_chaos {
-        let ret = tent_chaos {
-            value: Wrapping(value),
-            iterate_times: 0,
+impl<'a, W: Write> ciphertext_writer<'a, W> {
+    fn new(
+        ofile: &'a mut W,
+        keys: keystream,
+        piece_size: usize,
+        chunked: bool,
+    ) -> ciphertext_writer<'a, W> {
+        // rounded like the buffer of `ciphertext_reader`, so that the
+        // keystreams of both sides stay aligned
+        let piece_size: usize = piece_size.div_ceil(8) * 8;
+        return ciphertext_writer {
+            ofile,
+            keys,
+            buffer: vec![0xFF; piece_size],
+            piece_size,
+            filled: 0,
+            chunked,
         };
+    }
 
-        return ret;
+    fn write_piece(&mut self) -> std::io::Result<()> {
+        self.keys.apply(&mut self.buffer[0..self.filled]);
+
+        if self.chunked {
+            self.ofile
+                .write_all((self.filled as u64).to_le_bytes().as_slice())?;
+        }
+        self.ofile.write_all(&self.buffer[0..self.filled])?;
+        self.filled = 0;
+
+        return Ok(());
+    }
+
+    /// Writes the last piece and, for chunked output, the terminating empty chunk.
+    fn finish(mut self) -> std::io::Result<()> {
+        if self.filled > 0 {
+            self.write_piece()?;
+        }
+        if self.chunked {
+            self.ofile.write_all(0_u64.to_le_bytes().as_slice())?;
+        }
+        return Ok(());
     }
+}
 
-    pub fn iterate(&mut self) -> u64 {
-        let k = Wrapping(self.iterate_times << 2);
-        let g = self.value + k;
+impl<W: Write> Write for ciphertext_writer<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let piece_size: usize = min(self.piece_size as u64, self.keys.segment_left()) as usize;
+        let bytes: usize = min(piece_size - self.filled, buf.len());
 
-        let seperator = Wrapping(1_u64 << 63);
+        self.buffer[self.filled..(self.filled + bytes)].copy_from_slice(&buf[0..bytes]);
+        self.filled += bytes;
 
-        if g < seperator {
-            self.value = g << 1 + 1;
-        } else {
-            self.value = (Wrapping(!(0_u64)) - g) << 1;
+        if self.filled == piece_size {
+            self.write_piece()?;
         }
 
-        self.iterate_times += 1;
+        return Ok(bytes);
+    }
+
+    /// Only flushes the underlying stream, a partially filled piece is kept
+    /// because padding it would shift the keystream.
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.ofile.flush();
+    }
+}
+
+/// Encrypts `ifile` into `ofile`.
+///
+/// When `file_size` is known and the plaintext is not compressed, the
+/// ciphertext is written as a single `ciphertext` block. Otherwise, e.g. when
+/// reading from a pipe, it is written as a `ciphertext_stream` block: a
+/// sequence of chunks, each prefixed with its length as a little-endian u64
+/// and terminated by an empty chunk. Neither variant requires the output to
+/// be seekable.
+pub fn encryp_stream<R: Read, W: Write>(
+    ifile: &mut R,
+    ofile: &mut W,
+    opt: &encryp_option,
+    file_size: Option<u64>,
+) -> Result<(), String> {
+    let buffer_size: usize = opt.buffer_size;
+
+    opt.validate()?;
+
+    let opt = &derive_key(opt)?;
+
+    let ifile = &mut progress::progress_reader::new(ifile, opt, file_size);
+    let ofile = &mut mac::mac_writer::new(ofile, opt);
+    let keyed: bool = ofile.is_keyed();
 
-        return self.value.0;
+    let mut buffer: Vec<u8> = vec![0xFF; buffer_size];
+
+    // in auto mode the first buffer is read ahead as the compression sample
+    let mut read_ahead: Option<usize> = None;
+    if opt.compression.auto {
+        read_ahead = Some(read_full(ifile, &mut buffer).map_err(read_error)?);
+    }
+    let algorithm = opt.compression.choose(&buffer[0..read_ahead.unwrap_or(0)]);
+
+    write_header(ofile, opt)?;
+
+    if algorithm != compression_algorithm::none {
+        write_data_block(
+            ofile,
+            data_block_type::compression,
+            &compress::encode_block(algorithm, opt.compression.level),
+        )
+        .map_err(write_error)?;
+    }
+
+    if opt.padding != padding_policy::none {
+        write_data_block(ofile, data_block_type::padding, &opt.padding.encode_block())
+            .map_err(write_error)?;
+    }
+
+    if opt.segment_size != 0 {
+        write_data_block(
+            ofile,
+            data_block_type::segment_size,
+            opt.segment_size.to_le_bytes().as_slice(),
+        )
+        .map_err(write_error)?;
+    }
+
+    ofile.write_header_tag().map_err(write_error)?;
+
+    // the length of compressed or padded data is only known at the end
+    let chunked: bool = file_size.is_none()
+        || algorithm != compression_algorithm::none
+        || opt.padding != padding_policy::none;
+    if chunked {
+        write_data_block_head(ofile, data_block_type::ciphertext_stream, u64::MAX)
+    } else {
+        write_data_block_head(ofile, data_block_type::ciphertext, file_size.unwrap())
     }
+    .map_err(write_error)?;
+
+    let keys = if opt.segment_size != 0 {
+        keystream::segmented(opt, opt.segment_size)
+    } else {
+        keystream::single(opt, b"")
+    };
+
+    let mut cipher = ciphertext_writer::new(ofile, keys, buffer_size, chunked);
 
-    fn iterate_many_private(&mut self, times: u64, data: *mut u64, do_encrypt: bool) {
-        let mut k = Wrapping(self.iterate_times << 2);
-        let mut x = self.value;
+    let mut hasher = sha3::Sha3_512::new();
+    let mut segment_hasher = segment_hasher::new(opt.segment_size, mac::mac_key(opt));
+
+    let mut total_read: u64 = 0;
+
+    let mut padded = padding::padder::new(opt.padding, buffer_size, &mut cipher);
+
+    {
+        let mut writer = compress::compressor::new(algorithm, opt.compression.level, &mut padded)
+            .map_err(write_error)?;
 
-        let seperator = Wrapping(1_u64 << 63);
-        let mut it: u64 = 0;
         loop {
-            if it >= times {
-                break;
+            let read_bytes = match read_ahead.take() {
+                Some(bytes) => bytes,
+                None => read_full(ifile, &mut buffer).map_err(read_error)?,
+            };
+            total_read += read_bytes as u64;
+
+            if !keyed {
+                hasher.update(&buffer[0..read_bytes]);
             }
-            let g = x + k;
-            if g < seperator {
-                x = g << 1 + 1;
-            } else {
-                x = (Wrapping(!(0_u64)) - g) << 1;
+            if opt.segment_size != 0 {
+                segment_hasher.update(&buffer[0..read_bytes]);
             }
 
-            if !data.is_null() {
-                unsafe {
-                    if do_encrypt {
-                        *(data.add(it as usize)) ^= x.0;
-                    } else {
-                        *(data.add(it as usize)) = x.0;
-                    }
-                }
-            }
+            writer
+                .write_all(&buffer[0..read_bytes])
+                .map_err(write_error)?;
 
-            it += 1;
-            k += 4;
+            if read_bytes < buffer_size {
+                break;
+            }
         }
 
-        self.value = x;
-        self.iterate_times += times;
+        writer.finish().map_err(write_error)?;
     }
 
-    pub fn iterate_vec(&mut self, times: u64, vec: &mut [u64]) -> Result<(), String> {
-        if vec.len() != times as usize {
-            return Err(String::from("Size mismatch."));
+    padded.finish().map_err(write_error)?;
+    cipher.finish().map_err(write_error)?;
+
+    if let Some(size) = file_size {
+        if size != total_read {
+            return Err(format!(
+                "Source changed while encrypting : expected {} bytes but read {}.",
+                size, total_read
+            ));
         }
+    }
 
-        self.iterate_many_private(times, vec.as_mut_ptr(), false);
+    if !keyed {
+        write_data_block(
+            ofile,
+            data_block_type::sha3_512_original_file,
+            &hasher.finalize(),
+        )
+        .map_err(write_error)?;
+    }
 
-        return Ok(());
+    if opt.segment_size != 0 {
+        write_data_block(
+            ofile,
+            data_block_type::segment_hashes,
+            &segment_hasher.finish(),
+        )
+        .map_err(write_error)?;
     }
 
-    pub fn encrypt(&mut self, vec: &mut [u8]) -> Result<(), String> {
-        if vec.len() % 8 != 0 {
-            let mut err_msg =
-                String::from("Length of u8 array should be multiples of 8, but actually it is ");
+    ofile.finish().map_err(write_error)?;
+    ofile.flush().map_err(write_error)?;
 
-            err_msg.push_str(vec.len().to_string().as_str());
-            return Err(err_msg);
-        }
+    return Ok(());
+}
 
-        self.iterate_many_private(vec.len() as u64 / 8, vec.as_mut_ptr() as *mut u64, true);
+/// Segments are decrypted on their own, so their plaintext offsets must map
+/// directly to ciphertext offsets and every piece but the last one of a
+/// segment must use the keystream without padding.
+fn check_segmented(opt: &encryp_option) -> Result<(), String> {
+    if !opt.segment_size.is_multiple_of(8) {
+        return Err(format!(
+            "Segment size {} is not a multiple of 8.",
+            opt.segment_size
+        ));
+    }
+    if opt.segment_size > max_segment_size {
+        return Err(format!(
+            "Segment size {} is larger than {}.",
+            opt.segment_size, max_segment_size
+        ));
+    }
+    if !opt.buffer_size.is_multiple_of(8) {
+        return Err(format!(
+            "Buffer size {} is not a multiple of 8, which segmented files require.",
+            opt.buffer_size
+        ));
+    }
+    if opt.compression.algorithm != compression_algorithm::none {
+        return Err(String::from("Segmented files can not be compressed."));
+    }
+    if opt.padding != padding_policy::none {
+        return Err(String::from("Segmented files can not be padded."));
+    }
+    return Ok(());
+}
 
-        return Ok(());
+/// Result of an operation on `dst_name`, which is removed if the operation
+/// was cancelled.
+pub(crate) fn file_result(
+    result: Result<(), String>,
+    dst_name: &str,
+    opt: &encryp_option,
+) -> Result<(), encryp_error> {
+    match result {
+        Ok(()) => return Ok(()),
+        Err(_) if opt.cancel.is_cancelled() => {
+            let _ = fs::remove_file(dst_name);
+            return Err(encryp_error::cancelled);
+        }
+        Err(err) => return Err(encryp_error::failed(err)),
     }
+}
 
-    pub fn iterate_no_ret(&mut self, times: u64) {
-        let mut i = 0u64;
-        loop {
-            if i >= times {
-                break;
-            }
-            self.iterate();
-            i += 1;
+fn encryp_file_streams(
+    src_name: &str,
+    streams: file_streams_pair,
+    opt: &encryp_option,
+) -> Result<(), String> {
+    let mut ifile = streams.ifile;
+    let mut ofile = streams.ofile;
+
+    let mut opt: encryp_option = opt.clone();
+    opt.progress = opt.progress.for_file(src_name);
+    let opt = &opt;
+
+    let file_size: u64 = match ifile.metadata() {
+        Ok(meta) => meta.len(),
+        Err(err) => {
+            return Err(format!("Failed to get size of {} : {}", src_name, err));
         }
+    };
+
+    if opt.threads > 1 {
+        return encryp_parallel(&mut ifile, &mut ofile, opt, file_size);
+    }
+    return encryp_stream(&mut ifile, &mut ofile, opt, Some(file_size));
+}
+
+/// Like `encryp_file`, but returns the error instead of printing it.
+pub fn try_encryp_file(
+    src_name: &String,
+    dst_name: &String,
+    opt: &encryp_option,
+) -> Result<(), encryp_error> {
+    opt.validate().map_err(encryp_error::failed)?;
+    let streams = create_file_stream(src_name, dst_name, opt).map_err(encryp_error::failed)?;
+
+    let result = encryp_file_streams(src_name, streams, opt);
+    return file_result(result, dst_name, opt);
+}
+
+pub fn encryp_file(src_name: &String, dst_name: &String, opt: &encryp_option) -> bool {
+    if let Err(err) = try_encryp_file(src_name, dst_name, opt) {
+        eprintln!("Failed to encryp file {} : {}", src_name, err);
+        return false;
     }
+
+    return true;
 }
 
 //use hex_literal::hex;
@@ -411,442 +1037,725 @@ struct data_block_content {
 #[allow(non_camel_case_types)]
 struct encrypted_file {
     data_blocks: HashMap<data_block_type, data_block_content>,
+    /// Number of bytes consumed from the stream so far.
+    position: u64,
+    /// Raw bytes read by `parse_encrypted_file`, up to the head of the
+    /// ciphertext block.
+    header: Vec<u8>,
 }
 
-fn parse_encrypted_file(ifile: &mut fs::File) -> Result<encrypted_file, String> {
-    let mut file = encrypted_file {
-        data_blocks: HashMap::new(),
-    };
+/// Keeps a copy of everything read through it.
+#[allow(non_camel_case_types)]
+struct recording_reader<'a, R: Read> {
+    ifile: &'a mut R,
+    recorded: Vec<u8>,
+}
 
-    let mut buffer: Vec<u8> = Vec::new();
-    buffer.reserve(512);
+impl<R: Read> Read for recording_reader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes = self.ifile.read(buf)?;
+        self.recorded.extend_from_slice(&buf[0..bytes]);
+        return Ok(bytes);
+    }
+}
 
-    buffer.resize(16, 0xFF);
-    {
-        let ret = ifile.read_exact(buffer.as_mut_slice());
-        if ret.is_err() {
-            return Err(String::from("Failed to read file head."));
-        }
-
-        if buffer.len() != 16 {
-            return Err(String::from("length of buffer is not 16."));
-        }
-    }
-
-    for i in 0..=4 {
-        let idx = i as usize;
-        if buffer[idx] != file_head[idx] {
-            return Err(String::from("File head mismatch."));
-        }
-    }
+/// Reads the next data block into `file`, skipping unknown blocks.
+///
+/// Returns `None` at the end of the stream. The payload of a ciphertext block
+/// is left unread, so that the caller can decrypt it without seeking.
+fn read_data_block<R: Read>(
+    ifile: &mut R,
+    file: &mut encrypted_file,
+) -> Result<Option<data_block_type>, String> {
+    let mut buffer: Vec<u8> = vec![0xFF; 16];
 
     loop {
-        let mut blk_type: data_block_type = data_block_type::ciphertext;
-        let blk_len: u64;
-
-        let is_block_unknown: bool;
-
         buffer.resize(16, 0xFF);
 
-        let ret = ifile.read(buffer.as_mut_slice());
-
-        if ret.is_err() {
-            return Err(String::from("Failed to read a data block."));
-        } else {
-            let bytes: usize = ret.unwrap();
-            if bytes == 0 {
-                break;
+        match read_full(ifile, buffer.as_mut_slice()) {
+            Err(_) => {
+                return Err(String::from("Failed to read a data block."));
             }
-            if bytes != 16 {
-                return Err(String::from("Imcomplete data block."));
-            }
-        }
-
-        //blk_type = u64::from_le_bytes(buffer.);
-
-        unsafe {
-            let ptr: *const u64 = buffer.as_ptr() as *const u64;
-            blk_len = *(ptr.add(1));
-
-            let mut temp: data_block_type = data_block_type::sha3_512_original_file;
-
-            let ptr_temp = ((&mut temp) as *mut data_block_type) as *mut u64;
-
-            //let ptr_type: *mut u64 = (&mut blk_type) as *mut data_block_type as *mut u64;
-            *ptr_temp = *ptr;
-
-            match temp {
-                data_block_type::ciphertext
-                | data_block_type::hash_password
-                | data_block_type::salt_a
-                | data_block_type::salt_b
-                | data_block_type::sha3_512_original_file => {
-                    is_block_unknown = false;
-                    blk_type = temp;
+            Ok(bytes) => {
+                if bytes == 0 {
+                    return Ok(None);
                 }
-                #[allow(unreachable_patterns)]
-                _ => {
-                    println!("Warning : unknown data block {}", temp as u64);
-                    is_block_unknown = true;
+                if bytes != 16 {
+                    return Err(String::from("Imcomplete data block."));
                 }
             }
-
-            //let ptr_len: *mut u64 = (&mut blk_len) as *mut u64;
         }
-
-        let load_full_block: bool;
-
-        if is_block_unknown {
-            load_full_block = true;
-        } else {
-            match blk_type {
-                data_block_type::ciphertext => {
-                    load_full_block = false;
+        file.position += 16;
+
+        // integers of the format are little-endian
+        let blk_id: u64 = u64::from_le_bytes(buffer[0..8].try_into().unwrap());
+        let blk_len: u64 = u64::from_le_bytes(buffer[8..16].try_into().unwrap());
+
+        let blk_type: data_block_type = match data_block_type::from_u64(blk_id) {
+            Some(blk_type) => blk_type,
+            // blocks of later versions, skipped without a word so that
+            // nothing is printed into piped output
+            None => {
+                let skipped = std::io::copy(&mut ifile.take(blk_len), &mut std::io::sink())
+                    .map_err(read_error)?;
+                if skipped != blk_len {
+                    return Err(String::from("Unfinished data block"));
                 }
-                _ => load_full_block = true,
+                file.position += blk_len;
+                continue;
             }
-            //load_full_block = (blk_type != data_block_type::ciphertext);
-        }
+        };
 
-        if is_block_unknown {
-            ifile
-                .seek(std::io::SeekFrom::Current(blk_len as i64))
-                .expect("Failed to seek");
-            continue;
-        }
+        let load_full_block: bool = !blk_type.is_large();
 
         if file.data_blocks.contains_key(&blk_type) {
             return Err(String::from("More than one block have the same tag."));
         }
 
-        let blk_data: data_block_data;
-
-        let offset: u64 = ifile
-            .stream_position()
-            .expect("Failed to tell stream position");
+        let offset: u64 = file.position;
 
-        if load_full_block {
-            buffer.resize(blk_len as usize, 0xFF);
+        let blk_data: data_block_data = if load_full_block {
+            if blk_len > blk_type.max_len() {
+                return Err(format!(
+                    "{:?} block of {} bytes is too long.",
+                    blk_type, blk_len
+                ));
+            }
 
-            ifile
-                .read_exact(buffer.as_mut())
-                .expect("Unfinished data block");
+            // grows with what is actually read, not with the claimed length
+            let mut content: Vec<u8> = Vec::new();
+            let bytes = ifile
+                .take(blk_len)
+                .read_to_end(&mut content)
+                .map_err(read_error)?;
+            if bytes as u64 != blk_len {
+                return Err(String::from("Unfinished data block"));
+            }
+            file.position += blk_len;
 
-            blk_data = data_block_data::small(buffer.clone());
+            data_block_data::small(content)
         } else {
-            ifile
-                .seek(std::io::SeekFrom::Current(blk_len as i64))
-                .expect("Failed to seek");
-            blk_data = data_block_data::large(blk_len);
-        }
+            data_block_data::large(blk_len)
+        };
 
         file.data_blocks.insert(
             blk_type,
             data_block_content {
                 data: blk_data,
-                offset: offset,
+                offset,
             },
         );
-    }
 
-    return Ok(file);
+        return Ok(Some(blk_type));
+    }
 }
 
-fn get_salt(opt: &mut encryp_option, efile: &encrypted_file) -> Result<(), ()> {
-    // get salt_a
-    match efile.data_blocks.get(&data_block_type::salt_a) {
-        Some(content) => match &content.data {
-            data_block_data::small(v) => {
-                opt.salt_a = v.clone();
-            }
-            _ => {
-                eprintln!("{:?}", content.data);
-                return Err(());
-            }
-        },
-        None => {
-            eprintln!(
-                "File does not have data block {:?}",
-                data_block_type::salt_a
-            );
-            return Err(());
+/// Parses the file head and the data blocks in front of the ciphertext.
+///
+/// Parsing stops right after the head of the first large block, so the stream
+/// is never seeked and may be a pipe. Blocks behind the ciphertext are read by
+/// `parse_trailing_blocks` once the ciphertext has been consumed.
+fn parse_encrypted_file<R: Read>(ifile: &mut R) -> Result<encrypted_file, String> {
+    let mut file = encrypted_file {
+        data_blocks: HashMap::new(),
+        position: 0,
+        header: Vec::new(),
+    };
+
+    let mut recorder = recording_reader {
+        ifile,
+        recorded: Vec::new(),
+    };
+    let ifile = &mut recorder;
+
+    let mut buffer: Vec<u8> = vec![0xFF; 16];
+    {
+        let ret = ifile.read_exact(buffer.as_mut_slice());
+        if ret.is_err() {
+            return Err(String::from("Failed to read file head."));
+        }
+
+        if buffer.len() != 16 {
+            return Err(String::from("length of buffer is not 16."));
         }
     }
+    file.position = 16;
 
-    match efile.data_blocks.get(&data_block_type::salt_b) {
-        Some(content) => match &content.data {
-            data_block_data::small(v) => {
-                opt.salt_b = v.clone();
-            }
-            _ => {
-                eprintln!("{:?}", content.data);
-                return Err(());
-            }
-        },
-        None => {
-            eprintln!(
-                "File does not have data block {:?}",
-                data_block_type::salt_b
-            );
-            return Err(());
+    for i in 0..=4 {
+        let idx = i as usize;
+        if buffer[idx] != file_head[idx] {
+            return Err(String::from("File head mismatch."));
         }
     }
 
-    return Ok(());
+    loop {
+        match read_data_block(ifile, &mut file)? {
+            None => break,
+            Some(blk_type) if blk_type.is_large() => break,
+            Some(_) => {}
+        }
+    }
+
+    file.header = recorder.recorded;
+
+    return Ok(file);
 }
 
-fn get_hashes(efile: &encrypted_file) -> Result<(Vec<u8>, Vec<u8>), ()> {
-    let mut ret: (Vec<u8>, Vec<u8>) = (Vec::new(), Vec::new());
-    // hash_password
-    match efile.data_blocks.get(&data_block_type::hash_password) {
-        Some(content) => match &content.data {
-            data_block_data::small(v) => {
-                ret.0 = v.clone();
-            }
-            _ => {
-                eprintln!("{:?}", content.data);
-                return Err(());
+fn parse_trailing_blocks<R: Read>(ifile: &mut R, file: &mut encrypted_file) -> Result<(), String> {
+    loop {
+        match read_data_block(ifile, file)? {
+            None => break,
+            Some(blk_type) if blk_type.is_large() => {
+                return Err(String::from("More than one ciphertext block."));
             }
-        },
-        None => {
-            eprintln!(
-                "File does not have data block {:?}",
-                data_block_type::hash_password
-            );
-            return Err(());
+            Some(_) => {}
         }
     }
 
-    //original hash
-    match efile
-        .data_blocks
-        .get(&data_block_type::sha3_512_original_file)
-    {
+    return Ok(());
+}
+
+fn get_small_block(efile: &encrypted_file, blk_type: data_block_type) -> Result<Vec<u8>, String> {
+    match efile.data_blocks.get(&blk_type) {
         Some(content) => match &content.data {
             data_block_data::small(v) => {
-                ret.1 = v.clone();
+                return Ok(v.clone());
             }
             _ => {
-                eprintln!("{:?}", content.data);
-                return Err(());
+                return Err(format!("Unexpected content {:?}", content.data));
             }
         },
         None => {
-            eprintln!(
-                "File does not have data block {:?}",
-                data_block_type::sha3_512_original_file
-            );
-            return Err(());
+            return Err(format!("File does not have data block {:?}", blk_type));
         }
     }
-
-    return Ok(ret);
 }
 
-fn exmaine_password(opt: &encryp_option, password_hash: &Vec<u8>) -> bool {
-    let mut hasher = sha3::Sha3_512::new();
+fn get_salt(opt: &mut encryp_option, efile: &encrypted_file) -> Result<(), String> {
+    opt.salt_a = get_small_block(efile, data_block_type::salt_a)?;
+    opt.salt_b = get_small_block(efile, data_block_type::salt_b)?;
 
-    hasher.update(&opt.password);
-    hasher.update(&opt.salt_a);
-    let ret = hasher.finalize();
+    return Ok(());
+}
+
+fn exmaine_password(opt: &encryp_option, password_hash: &[u8]) -> bool {
+    let ret = hash_password(opt);
 
     if ret.len() != password_hash.len() {
         return false;
     }
 
     for idx in 0..ret.len() {
-        let idx = idx as usize;
         if ret[idx] != password_hash[idx] {
             return false;
         }
     }
     return true;
 }
+/// Loads the salts, the cipher and the key derivation of `efile` into a copy
+/// of `opt` and derives the key from its password.
+fn load_key(efile: &encrypted_file, opt: &encryp_option) -> Result<encryp_option, String> {
+    let mut opt: encryp_option = opt.clone();
+
+    get_salt(&mut opt, efile)?;
+
+    opt.cipher = get_cipher(efile)?;
+
+    opt.kdf = get_kdf(efile)?.unwrap_or(kdf_algorithm::legacy);
+    opt.authenticated = opt.kdf != kdf_algorithm::legacy
+        || efile.data_blocks.contains_key(&data_block_type::header_tag);
+    opt.derived_key = None;
+    return derive_key(&opt);
+}
+
+fn get_cipher(efile: &encrypted_file) -> Result<cipher_algorithm, String> {
+    if !efile.data_blocks.contains_key(&data_block_type::cipher) {
+        return Ok(cipher_algorithm::tent_chaos);
+    }
+    let block = get_small_block(efile, data_block_type::cipher)?;
+    return cipher_algorithm::decode_block(&block);
+}
+
+/// `None` if `efile` has no kdf block.
+fn get_kdf(efile: &encrypted_file) -> Result<Option<kdf_algorithm>, String> {
+    if !efile.data_blocks.contains_key(&data_block_type::kdf) {
+        return Ok(None);
+    }
+    let block = get_small_block(efile, data_block_type::kdf)?;
+    return Ok(Some(kdf_algorithm::decode_block(&block)?));
+}
+
+/// Loads the salts of `efile` into a copy of `opt` and checks the password
+/// and the header tag.
+fn unlock(efile: &encrypted_file, opt: &encryp_option) -> Result<encryp_option, String> {
+    let opt = load_key(efile, opt)?;
+
+    let password_hash = get_small_block(efile, data_block_type::hash_password)?;
+
+    if !exmaine_password(&opt, &password_hash) {
+        return Err(String::from("Wrong password."));
+    }
+
+    mac::verify_header_tag(efile, &opt)?;
+
+    return Ok(opt);
+}
+
 #[allow(non_camel_case_types)]
 struct ciphertext_info {
-    length: u64,
+    /// `None` for a `ciphertext_stream` block of unknown length.
+    length: Option<u64>,
     offset: u64,
 }
 
-//(lenght,offset)
-fn get_ciphertext_info(efile: &encrypted_file) -> Result<ciphertext_info, ()> {
-    let mut ret: ciphertext_info = ciphertext_info {
-        length: 0,
-        offset: 0,
-    };
-
-    match efile.data_blocks.get(&data_block_type::ciphertext) {
-        Some(content) => {
-            ret.offset = content.offset;
-
+fn get_ciphertext_info(efile: &encrypted_file) -> Result<ciphertext_info, String> {
+    for (blk_type, length) in [
+        (data_block_type::ciphertext, true),
+        (data_block_type::ciphertext_stream, false),
+    ] {
+        if let Some(content) = efile.data_blocks.get(&blk_type) {
             match &content.data {
-                data_block_data::large(bytes) => {
-                    ret.length = *bytes;
+                // the length of a stream is only known from its chunks
+                data_block_data::large(bytes) if length || *bytes == u64::MAX => {
+                    return Ok(ciphertext_info {
+                        length: if length { Some(*bytes) } else { None },
+                        offset: content.offset,
+                    });
                 }
                 _ => {
-                    return Err(());
+                    return Err(String::from("Malformed ciphertext block."));
                 }
             }
         }
-        None => {
-            return Err(());
+    }
+
+    return Err(String::from("cipher text not found."));
+}
+
+/// Reads and decrypts the payload of a ciphertext block.
+///
+/// The payload is decrypted in pieces of at most the buffer size, padded to a
+/// multiple of 8 only at the end of a chunk, mirroring `ciphertext_writer`.
+#[allow(non_camel_case_types)]
+struct ciphertext_reader<'a, R: Read> {
+    ifile: &'a mut R,
+    keys: keystream,
+    buffer: Vec<u8>,
+    begin: usize,
+    end: usize,
+    /// Bytes left in the current chunk, or in the whole ciphertext block.
+    chunk_left: u64,
+    chunked: bool,
+    finished: bool,
+    /// Bytes consumed from `ifile`, including chunk lengths.
+    consumed: u64,
+}
+
+impl<'a, R: Read> ciphertext_reader<'a, R> {
+    /// `length` is `None` for `ciphertext_stream` blocks.
+    fn new(
+        ifile: &'a mut R,
+        keys: keystream,
+        buffer_size: usize,
+        length: Option<u64>,
+    ) -> ciphertext_reader<'a, R> {
+        return ciphertext_reader {
+            ifile,
+            keys,
+            // keep the buffer a multiple of 8 so that the keystream stays aligned
+            buffer: vec![0xFF; buffer_size.div_ceil(8) * 8],
+            begin: 0,
+            end: 0,
+            chunk_left: length.unwrap_or(0),
+            chunked: length.is_none(),
+            finished: false,
+            consumed: 0,
+        };
+    }
+
+    fn fill_buffer(&mut self) -> std::io::Result<()> {
+        if self.chunk_left == 0 {
+            if !self.chunked {
+                self.finished = true;
+                return Ok(());
+            }
+
+            let mut chunk_len = [0_u8; 8];
+            if read_full(self.ifile, &mut chunk_len)? != 8 {
+                return Err(truncated_error());
+            }
+            self.consumed += 8;
+
+            self.chunk_left = u64::from_le_bytes(chunk_len);
+            if self.chunk_left == 0 {
+                self.finished = true;
+                return Ok(());
+            }
+        }
+
+        let bytes_wanted: usize = min(
+            min(self.buffer.len() as u64, self.chunk_left),
+            self.keys.segment_left(),
+        ) as usize;
+
+        let bytes_read = read_full(self.ifile, &mut self.buffer[0..bytes_wanted])?;
+        if bytes_read != bytes_wanted {
+            return Err(truncated_error());
         }
+        self.consumed += bytes_read as u64;
+        self.chunk_left -= bytes_read as u64;
+
+        self.keys.apply(&mut self.buffer[0..bytes_read]);
+
+        self.begin = 0;
+        self.end = bytes_read;
+
+        return Ok(());
     }
 
-    return Ok(ret);
+    /// Skips whatever the caller did not read and returns the bytes consumed.
+    fn finish(mut self) -> std::io::Result<u64> {
+        while !self.finished {
+            self.fill_buffer()?;
+        }
+        return Ok(self.consumed);
+    }
 }
 
-use std::cmp::min;
+fn truncated_error() -> std::io::Error {
+    return std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "Ciphertext is truncated.",
+    );
+}
 
-pub fn decrypt_file(src_name: &String, __opt: &encryp_option, dst_dst_name: &mut String) -> bool {
-    let mut opt: encryp_option = __opt.clone();
+impl<R: Read> Read for ciphertext_reader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.begin == self.end {
+            if self.finished {
+                return Ok(0);
+            }
+            self.fill_buffer()?;
+        }
 
-    if !src_name.ends_with(suffix) {
-        eprintln!(
-            "Fatal error : extension of source file {} is not {}.",
-            src_name, suffix
-        );
-        return false;
+        let bytes: usize = min(self.end - self.begin, buf.len());
+        buf[0..bytes].copy_from_slice(&self.buffer[self.begin..(self.begin + bytes)]);
+        self.begin += bytes;
+
+        return Ok(bytes);
     }
+}
 
-    let dst_name = &src_name[0..(src_name.len() - suffix.len())];
+fn get_compression(efile: &encrypted_file) -> Result<compression_algorithm, String> {
+    if !efile
+        .data_blocks
+        .contains_key(&data_block_type::compression)
+    {
+        return Ok(compression_algorithm::none);
+    }
 
-    *dst_dst_name = String::from(dst_name);
+    let block = get_small_block(efile, data_block_type::compression)?;
+    return compress::decode_block(&block);
+}
 
-    let streams = create_file_stream(src_name, dst_dst_name, __opt);
-    if streams.is_err() {
-        println!("{:?}", streams.err());
-        return false;
+fn get_padding(efile: &encrypted_file) -> Result<padding_policy, String> {
+    if !efile.data_blocks.contains_key(&data_block_type::padding) {
+        return Ok(padding_policy::none);
     }
 
-    let streams = streams.unwrap();
+    let block = get_small_block(efile, data_block_type::padding)?;
+    return padding_policy::decode_block(&block);
+}
 
-    let mut ifile = streams.ifile;
+/// Segment size of a segmented file, 0 for a file with a single keystream.
+fn get_segment_size(efile: &encrypted_file) -> Result<u64, String> {
+    if !efile
+        .data_blocks
+        .contains_key(&data_block_type::segment_size)
+    {
+        return Ok(0);
+    }
 
-    let efile = parse_encrypted_file(&mut ifile);
+    let block = get_small_block(efile, data_block_type::segment_size)?;
+    if block.len() != 8 {
+        return Err(format!(
+            "Invalid segment size block of {} bytes.",
+            block.len()
+        ));
+    }
 
-    if efile.is_err() {
-        eprintln!("{}", efile.err().unwrap());
-        return false;
+    let mut segment_size = [0_u8; 8];
+    segment_size.copy_from_slice(&block);
+    let segment_size = u64::from_le_bytes(segment_size);
+
+    if segment_size == 0 || !segment_size.is_multiple_of(8) || segment_size > max_segment_size {
+        return Err(format!("Invalid segment size {}.", segment_size));
     }
 
-    let efile = efile.unwrap();
+    return Ok(segment_size);
+}
 
-    if get_salt(&mut opt, &efile).is_err() {
-        return false;
+fn tree_hash(segment_hashes: &[u8]) -> Vec<u8> {
+    return sha3::Sha3_512::digest(segment_hashes).to_vec();
+}
+
+/// Checks the hashes of the decrypted segments against the `segment_hashes`
+/// block, and against the `sha3_512_tree` block if there is one.
+fn verify_segment_hashes(efile: &encrypted_file, segment_hashes: &[u8]) -> Result<(), String> {
+    if segment_hashes != get_small_block(efile, data_block_type::segment_hashes)? {
+        return Err(String::from("Segment checksum failed."));
     }
 
-    let hashes = get_hashes(&efile);
-    if hashes.is_err() {
-        return false;
+    if efile
+        .data_blocks
+        .contains_key(&data_block_type::sha3_512_tree)
+        && tree_hash(segment_hashes) != get_small_block(efile, data_block_type::sha3_512_tree)?
+    {
+        return Err(String::from("sha3-512 tree checksum failed."));
     }
 
-    let hashes = hashes.unwrap();
+    return Ok(());
+}
 
-    if !exmaine_password(&opt, &hashes.0) {
-        println!(
-            "Error : Failed to decrypt file {} : Wrong password.",
-            src_name
-        );
-        return false;
+fn file_keystream(opt: &encryp_option, segment_size: u64) -> keystream {
+    if segment_size != 0 {
+        return keystream::segmented(opt, segment_size);
     }
+    return keystream::single(opt, b"");
+}
+
+/// Decrypts `ifile` into `ofile` without seeking either of them.
+///
+/// The plaintext is written before the trailing checksum can be read, so a
+/// failed checksum is reported only after the whole output has been written.
+pub fn decrypt_stream<R: Read, W: Write>(
+    ifile: &mut R,
+    ofile: &mut W,
+    __opt: &encryp_option,
+) -> Result<(), String> {
+    __opt.validate()?;
+
+    let mut authenticated = mac::mac_reader::new(ifile);
+
+    let mut efile = parse_encrypted_file(&mut authenticated)?;
+
+    let opt = unlock(&efile, __opt)?;
+    authenticated.set_key(&opt);
+
+    let cipher_info = get_ciphertext_info(&efile)?;
+
+    let algorithm = get_compression(&efile)?;
+
+    let policy = get_padding(&efile)?;
+
+    let segment_size = get_segment_size(&efile)?;
 
+    let key = mac::mac_key(&opt);
+    let keyed: bool = key.is_some();
     let mut hasher = sha3::Sha3_512::new();
+    let mut segment_hasher = segment_hasher::new(segment_size, key);
 
-    let mut buffer: Vec<u8> = Vec::new();
+    let mut buffer: Vec<u8> = vec![0xFF; opt.buffer_size];
 
-    buffer.resize(opt.buffer_size, 0xFF);
+    let keys = file_keystream(&opt, segment_size);
 
-    let mut ofile = streams.ofile;
+    let ifile = &mut progress::progress_reader::new(&mut authenticated, &opt, cipher_info.length);
 
-    let cipher_info = get_ciphertext_info(&efile);
+    let mut cipher = ciphertext_reader::new(ifile, keys, opt.buffer_size, cipher_info.length);
 
-    if cipher_info.is_err() {
-        eprintln!(
-            "Failed to decrypt file {} : cipher text not found.",
-            src_name
-        );
-        return false;
+    {
+        let unpadded = padding::unpadder::new(policy, &mut cipher);
+        let mut reader = compress::decompressor::new(algorithm, unpadded).map_err(read_error)?;
+
+        loop {
+            let bytes_read = read_full(&mut reader, &mut buffer).map_err(read_error)?;
+            if bytes_read == 0 {
+                break;
+            }
+
+            if !keyed {
+                hasher.update(&buffer[0..bytes_read]);
+            }
+            if segment_size != 0 {
+                segment_hasher.update(&buffer[0..bytes_read]);
+            }
+
+            ofile
+                .write_all(&buffer[0..bytes_read])
+                .map_err(write_error)?;
+        }
     }
 
-    let cipher_info = cipher_info.unwrap();
+    efile.position += cipher.finish().map_err(read_error)?;
 
-    ifile
-        .seek(std::io::SeekFrom::Start(cipher_info.offset))
-        .expect("Failed to seek.");
+    // past the ciphertext, which is all the progress counts
+    parse_trailing_blocks(&mut authenticated, &mut efile)?;
 
-    let mut total_read: u64 = 0;
+    if authenticated.verify(&efile)? {
+        // the MAC covers the whole file
+    } else if efile
+        .data_blocks
+        .contains_key(&data_block_type::sha3_512_original_file)
+    {
+        let original_hash = get_small_block(&efile, data_block_type::sha3_512_original_file)?;
 
-    let mut tent = tent_chaos::new(compute_initial_x(&opt));
+        let sha3_512_file = hasher.finalize();
 
-    loop {
-        let bytes_read = ifile.read(buffer.as_mut());
+        if sha3_512_file.len() != original_hash.len() {
+            return Err(format!(
+                "Lenght of hash mismatch : {} and {}",
+                sha3_512_file.len(),
+                original_hash.len()
+            ));
+        }
 
-        if let Err(err) = bytes_read {
-            eprintln!("Failed to read from file {}, detail : {:?}", src_name, err);
-            return false;
+        for i in 0..sha3_512_file.len() {
+            if sha3_512_file[i] != original_hash[i] {
+                return Err(String::from("sha3-512 checksum failed."));
+            }
         }
+    } else if !efile
+        .data_blocks
+        .contains_key(&data_block_type::sha3_512_tree)
+    {
+        return Err(String::from("sha3-512 checksum not found."));
+    }
 
-        let bytes_read = bytes_read.unwrap();
+    if segment_size != 0 {
+        verify_segment_hashes(&efile, &segment_hasher.finish())?;
+    }
 
-        let bytes_avaliable: u64 = min(bytes_read as u64, cipher_info.length - total_read);
+    ofile.flush().map_err(write_error)?;
 
-        total_read += bytes_read as u64;
+    return Ok(());
+}
 
-        let bytes_avaliable_ceil: usize = {
-            if bytes_avaliable % 8 == 0 {
-                bytes_avaliable as usize
-            } else {
-                (bytes_avaliable as usize | 0b111) + 1
-                //buffer_size
-            }
-        };
+/// A data block as listed by `inspect_stream`.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone)]
+pub struct data_block_info {
+    pub block_type: data_block_type,
+    /// Offset of the block content from the beginning of the file.
+    pub offset: u64,
+    /// Length of the block content. For a `ciphertext_stream` block this is
+    /// the total length of its chunks, without their length prefixes.
+    pub length: u64,
+}
 
-        tent.encrypt(&mut buffer[0..bytes_avaliable_ceil])
-            .expect("Tent chaos failed to decrpyt.");
+fn skip_bytes<R: Read>(ifile: &mut R, len: u64) -> Result<(), String> {
+    let skipped = std::io::copy(&mut ifile.take(len), &mut std::io::sink()).map_err(read_error)?;
+    if skipped != len {
+        return Err(String::from("Ciphertext is truncated."));
+    }
+    return Ok(());
+}
 
-        hasher.update(&buffer[0..bytes_avaliable as usize]);
+/// Skips the payload of the ciphertext block and returns its length.
+fn skip_ciphertext<R: Read>(
+    ifile: &mut R,
+    efile: &mut encrypted_file,
+    length: Option<u64>,
+) -> Result<u64, String> {
+    if let Some(length) = length {
+        skip_bytes(ifile, length)?;
+        efile.position += length;
+        return Ok(length);
+    }
 
-        ofile
-            .write(&buffer[0..bytes_avaliable as usize])
-            .expect("Failed to write.");
+    let mut total: u64 = 0;
+    loop {
+        let mut chunk_len = [0_u8; 8];
+        if ifile.read_exact(&mut chunk_len).is_err() {
+            return Err(String::from("Ciphertext is truncated."));
+        }
+        let chunk_len = u64::from_le_bytes(chunk_len);
+        efile.position += 8;
 
-        if total_read >= cipher_info.length {
+        if chunk_len == 0 {
             break;
         }
+
+        skip_bytes(ifile, chunk_len)?;
+        efile.position += chunk_len;
+        total += chunk_len;
     }
 
-    let sha3_512_file = hasher.finalize();
+    return Ok(total);
+}
 
-    if sha3_512_file.len() != hashes.1.len() {
-        eprintln!(
-            "Lenght of hash mismatch : {} and {}",
-            sha3_512_file.len(),
-            hashes.1.len()
-        );
+/// Lists the known data blocks of an encrypted file in the order they are
+/// stored. No password is needed, so only what is visible without one is
+/// reported: the lengths of a padded ciphertext are the padded lengths.
+pub fn inspect_stream<R: Read>(ifile: &mut R) -> Result<Vec<data_block_info>, String> {
+    let mut efile = parse_encrypted_file(ifile)?;
 
-        return false;
-    }
+    let cipher_len: u64 = match efile.data_blocks.get(&data_block_type::archive_payload) {
+        Some(content) => match content.data {
+            data_block_data::large(length) => skip_ciphertext(ifile, &mut efile, Some(length))?,
+            data_block_data::small(_) => return Err(String::from("Malformed archive payload.")),
+        },
+        None => {
+            let cipher_info = get_ciphertext_info(&efile)?;
+            skip_ciphertext(ifile, &mut efile, cipher_info.length)?
+        }
+    };
 
-    for i in 0..sha3_512_file.len() {
-        let i = i as usize;
+    parse_trailing_blocks(ifile, &mut efile)?;
 
-        if sha3_512_file[i] != hashes.1[i] {
-            eprintln!("Failed to decrpyt file : sha3-512 checksum failed.");
-            return false;
-        }
+    let mut ret: Vec<data_block_info> = Vec::new();
+    for (blk_type, content) in &efile.data_blocks {
+        let length: u64 = match &content.data {
+            data_block_data::small(v) => v.len() as u64,
+            data_block_data::large(_) => cipher_len,
+        };
+
+        ret.push(data_block_info {
+            block_type: *blk_type,
+            offset: content.offset,
+            length,
+        });
     }
+    ret.sort_by_key(|info| info.offset);
+
+    return Ok(ret);
+}
+
+fn decrypt_file_streams(
+    src_name: &str,
+    streams: file_streams_pair,
+    opt: &encryp_option,
+) -> Result<(), String> {
+    let mut ifile = streams.ifile;
+    let mut ofile = streams.ofile;
 
-    println!("success");
+    let mut opt: encryp_option = opt.clone();
+    opt.progress = opt.progress.for_file(src_name);
+    let opt = &opt;
 
-    /*
-    for blk in efile.unwrap().data_blocks {
-        println!("key = {:?}, value = {:?}", blk.0, blk.1);
+    if opt.threads > 1 {
+        return decrypt_parallel(&mut ifile, &mut ofile, opt);
     }
+    return decrypt_stream(&mut ifile, &mut ofile, opt);
+}
+
+/// Like `decrypt_file`, but returns the error instead of printing it.
+pub fn try_decrypt_file(
+    src_name: &String,
+    dst_name: &String,
+    opt: &encryp_option,
+) -> Result<(), encryp_error> {
+    opt.validate().map_err(encryp_error::failed)?;
+    let streams = create_file_stream(src_name, dst_name, opt).map_err(encryp_error::failed)?;
 
-    */
+    let result = decrypt_file_streams(src_name, streams, opt);
+    return file_result(result, dst_name, opt);
+}
 
-    //println!("src = {}, dst = {}", src_name, dst_name);
+pub fn decrypt_file(src_name: &String, dst_name: &String, opt: &encryp_option) -> bool {
+    if let Err(err) = try_decrypt_file(src_name, dst_name, opt) {
+        eprintln!("Failed to decrypt file {} : {}", src_name, err);
+        return false;
+    }
 
     return true;
 }
@@ -0,0 +1,193 @@
+/// How the key is derived from the password.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum kdf_algorithm {
+    /// The password is hashed together with the salts as is, like files
+    /// written before key derivation was added. Fast to brute force.
+    legacy,
+    /// Argon2id with salt B.
+    argon2id {
+        /// Memory in KiB.
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+}
+
+/// Length of a derived key.
+#[allow(non_upper_case_globals)]
+const key_len: usize = 64;
+
+/// Files asking for more memory, 1 GiB, are refused rather than allowed to
+/// exhaust it.
+#[allow(non_upper_case_globals)]
+const max_memory: u32 = 1024 * 1024;
+
+/// Likewise for files that would take hours to unlock.
+#[allow(non_upper_case_globals)]
+const max_iterations: u32 = 64;
+
+impl kdf_algorithm {
+    /// Argon2id with the parameters recommended by RFC 9106 for memory
+    /// constrained environments.
+    pub fn argon2id_default() -> kdf_algorithm {
+        return kdf_algorithm::argon2id {
+            memory: 64 * 1024,
+            iterations: 3,
+            parallelism: 4,
+        };
+    }
+
+    fn argon2_params(
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+    ) -> Result<argon2::Params, String> {
+        if memory > max_memory {
+            return Err(format!("argon2id memory of {} KiB is too large.", memory));
+        }
+        if iterations > max_iterations {
+            return Err(format!("{} argon2id iterations are too many.", iterations));
+        }
+        return argon2::Params::new(memory, iterations, parallelism, Some(key_len))
+            .map_err(|e| format!("Invalid argon2id parameters : {}", e));
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), String> {
+        match self {
+            kdf_algorithm::legacy => return Ok(()),
+            kdf_algorithm::argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                kdf_algorithm::argon2_params(*memory, *iterations, *parallelism)?;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Derives the key from `password` and `salt`. `None` for `legacy`,
+    /// which uses the password directly.
+    pub(crate) fn derive(&self, password: &[u8], salt: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        match self {
+            kdf_algorithm::legacy => return Ok(None),
+            kdf_algorithm::argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => {
+                let params = kdf_algorithm::argon2_params(*memory, *iterations, *parallelism)?;
+                let argon2 = argon2::Argon2::new(
+                    argon2::Algorithm::Argon2id,
+                    argon2::Version::V0x13,
+                    params,
+                );
+
+                let mut key: Vec<u8> = vec![0; key_len];
+                argon2
+                    .hash_password_into(password, salt, &mut key)
+                    .map_err(|e| format!("Failed to derive key : {}", e))?;
+                return Ok(Some(key));
+            }
+        }
+    }
+
+    /// Content of the `kdf` data block: the algorithm followed by its
+    /// parameters, all as little-endian 64-bit integers.
+    pub(crate) fn encode_block(&self) -> Vec<u8> {
+        let params: [u64; 4] = match self {
+            kdf_algorithm::legacy => [0, 0, 0, 0],
+            kdf_algorithm::argon2id {
+                memory,
+                iterations,
+                parallelism,
+            } => [1, *memory as u64, *iterations as u64, *parallelism as u64],
+        };
+
+        let mut ret: Vec<u8> = Vec::with_capacity(32);
+        for param in params {
+            ret.extend_from_slice(param.to_le_bytes().as_slice());
+        }
+        return ret;
+    }
+
+    pub(crate) fn decode_block(data: &[u8]) -> Result<kdf_algorithm, String> {
+        if data.len() != 32 {
+            return Err(format!("Invalid kdf block of {} bytes.", data.len()));
+        }
+
+        let mut params = [0_u64; 4];
+        for (idx, param) in params.iter_mut().enumerate() {
+            let mut bytes = [0_u8; 8];
+            bytes.copy_from_slice(&data[(idx * 8)..(idx * 8 + 8)]);
+            *param = u64::from_le_bytes(bytes);
+        }
+
+        let to_u32 = |value: u64| -> Result<u32, String> {
+            return u32::try_from(value).map_err(|_| format!("Invalid kdf parameter {}.", value));
+        };
+
+        match params[0] {
+            0 => return Ok(kdf_algorithm::legacy),
+            1 => {
+                let ret = kdf_algorithm::argon2id {
+                    memory: to_u32(params[1])?,
+                    iterations: to_u32(params[2])?,
+                    parallelism: to_u32(params[3])?,
+                };
+                ret.validate()?;
+                return Ok(ret);
+            }
+            other => return Err(format!("Unknown kdf {}.", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_round_trip() {
+        for kdf in [kdf_algorithm::legacy, kdf_algorithm::argon2id_default()] {
+            assert_eq!(kdf_algorithm::decode_block(&kdf.encode_block()), Ok(kdf));
+        }
+
+        assert!(kdf_algorithm::decode_block(&[0; 24]).is_err());
+
+        let mut unknown = kdf_algorithm::legacy.encode_block();
+        unknown[0] = 2;
+        assert!(kdf_algorithm::decode_block(&unknown).is_err());
+
+        let too_much_memory = kdf_algorithm::argon2id {
+            memory: max_memory + 1,
+            iterations: 1,
+            parallelism: 1,
+        };
+        assert!(kdf_algorithm::decode_block(&too_much_memory.encode_block()).is_err());
+
+        let too_many_iterations = kdf_algorithm::argon2id {
+            memory: 64,
+            iterations: max_iterations + 1,
+            parallelism: 1,
+        };
+        assert!(kdf_algorithm::decode_block(&too_many_iterations.encode_block()).is_err());
+    }
+
+    #[test]
+    fn derive_depends_on_password_and_salt() {
+        let kdf = kdf_algorithm::argon2id {
+            memory: 64,
+            iterations: 1,
+            parallelism: 1,
+        };
+        let key = kdf.derive(b"neko", &[1; 16]).unwrap().unwrap();
+        assert_eq!(key.len(), key_len);
+        assert_eq!(kdf.derive(b"neko", &[1; 16]).unwrap().unwrap(), key);
+        assert_ne!(kdf.derive(b"nya", &[1; 16]).unwrap().unwrap(), key);
+        assert_ne!(kdf.derive(b"neko", &[2; 16]).unwrap().unwrap(), key);
+
+        assert_eq!(kdf_algorithm::legacy.derive(b"neko", &[1; 16]), Ok(None));
+    }
+}
@@ -0,0 +1,305 @@
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use hmac::{Hmac, Mac};
+use sha3::Digest;
+
+use crate::{
+    data_block_type, encryp_option, encrypted_file, get_small_block, parse_encrypted_file,
+    parse_trailing_blocks, read_error, write_data_block,
+};
+
+#[allow(non_camel_case_types)]
+type hmac_sha3_512 = Hmac<sha3::Sha3_512>;
+
+/// Length of the content of a `mac` block.
+#[allow(non_upper_case_globals)]
+const mac_len: u64 = 64;
+
+/// Length of a whole `mac` block, which is not covered by the MAC itself.
+#[allow(non_upper_case_globals)]
+const mac_block_len: usize = 16 + mac_len as usize;
+
+/// Key for `purpose`, derived from the key material and salt B of `opt`.
+///
+/// `None` for files that are not authenticated, which keep the unkeyed
+/// SHA3-512 checksums of the legacy format and have no header tag.
+fn subkey(opt: &encryp_option, purpose: &[u8]) -> Option<Vec<u8>> {
+    if !opt.authenticated {
+        return None;
+    }
+
+    let mut hasher = sha3::Sha3_512::new();
+    hasher.update(b"neko-encrypt ");
+    hasher.update(purpose);
+    hasher.update(opt.key_material());
+    hasher.update(&opt.salt_b);
+    return Some(hasher.finalize().to_vec());
+}
+
+/// Key of the MAC and of the segment hashes.
+pub(crate) fn mac_key(opt: &encryp_option) -> Option<Vec<u8>> {
+    return subkey(opt, b"mac");
+}
+
+fn header_key(opt: &encryp_option) -> Option<Vec<u8>> {
+    return subkey(opt, b"header");
+}
+
+fn new_mac(key: &[u8]) -> hmac_sha3_512 {
+    return hmac_sha3_512::new_from_slice(key).expect("HMAC takes keys of any length");
+}
+
+/// Hash of a plaintext segment, an HMAC-SHA3-512 if there is a key and a
+/// plain SHA3-512 otherwise.
+#[allow(non_camel_case_types)]
+pub(crate) enum segment_digest {
+    plain(sha3::Sha3_512),
+    keyed(hmac_sha3_512),
+}
+
+impl segment_digest {
+    pub(crate) fn new(key: Option<&[u8]>) -> segment_digest {
+        match key {
+            Some(key) => return segment_digest::keyed(new_mac(key)),
+            None => return segment_digest::plain(sha3::Sha3_512::new()),
+        }
+    }
+
+    pub(crate) fn digest(key: Option<&[u8]>, data: &[u8]) -> Vec<u8> {
+        let mut digest = segment_digest::new(key);
+        digest.update(data);
+        return digest.finalize();
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) {
+        match self {
+            segment_digest::plain(hasher) => Digest::update(hasher, data),
+            segment_digest::keyed(mac) => Mac::update(mac, data),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Vec<u8> {
+        match self {
+            segment_digest::plain(hasher) => return hasher.finalize().to_vec(),
+            segment_digest::keyed(mac) => return mac.finalize().into_bytes().to_vec(),
+        }
+    }
+}
+
+/// Passes everything written to it on to `ofile` and computes the header
+/// tag, written by `write_header_tag`, and the MAC, appended by `finish`.
+#[allow(non_camel_case_types)]
+pub(crate) struct mac_writer<'a, W: Write> {
+    ofile: &'a mut W,
+    header: Option<hmac_sha3_512>,
+    mac: Option<hmac_sha3_512>,
+}
+
+impl<'a, W: Write> mac_writer<'a, W> {
+    /// `opt` must hold the derived key, if any. A file that is not
+    /// authenticated gets no MAC.
+    pub(crate) fn new(ofile: &'a mut W, opt: &encryp_option) -> mac_writer<'a, W> {
+        return mac_writer {
+            ofile,
+            header: header_key(opt).map(|key| new_mac(&key)),
+            mac: mac_key(opt).map(|key| new_mac(&key)),
+        };
+    }
+
+    pub(crate) fn is_keyed(&self) -> bool {
+        return self.mac.is_some();
+    }
+
+    /// Writes the `header_tag` block if the file is authenticated. Must be
+    /// called right before the head of the ciphertext block.
+    pub(crate) fn write_header_tag(&mut self) -> std::io::Result<()> {
+        if let Some(header) = self.header.take() {
+            write_data_block(
+                self,
+                data_block_type::header_tag,
+                &header.finalize().into_bytes(),
+            )?;
+        }
+        return Ok(());
+    }
+
+    /// Writes the `mac` block if the file is authenticated. Nothing must be
+    /// written after it.
+    pub(crate) fn finish(&mut self) -> std::io::Result<()> {
+        if let Some(mac) = self.mac.take() {
+            write_data_block(
+                self.ofile,
+                data_block_type::mac,
+                &mac.finalize().into_bytes(),
+            )?;
+        }
+        return Ok(());
+    }
+}
+
+impl<W: Write> Write for mac_writer<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let bytes = self.ofile.write(buf)?;
+        if let Some(header) = &mut self.header {
+            header.update(&buf[0..bytes]);
+        }
+        if let Some(mac) = &mut self.mac {
+            mac.update(&buf[0..bytes]);
+        }
+        return Ok(bytes);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.ofile.flush();
+    }
+}
+
+/// Checks the `header_tag` block of `efile` with the key of the unlocked
+/// `opt`, if the file is authenticated. Whether it is can't be changed by
+/// dropping the tag, as the password hash depends on it.
+pub(crate) fn verify_header_tag(efile: &encrypted_file, opt: &encryp_option) -> Result<(), String> {
+    let key = match header_key(opt) {
+        Some(key) => key,
+        None => return Ok(()),
+    };
+
+    let offset = match efile.data_blocks.get(&data_block_type::header_tag) {
+        Some(content) => content.offset,
+        None => return Err(String::from("Header tag not found.")),
+    };
+    // nothing may come between the tag and the head of the ciphertext block
+    if offset + mac_len + 16 != efile.header.len() as u64 {
+        return Err(String::from(
+            "Header tag is not in front of the ciphertext.",
+        ));
+    }
+
+    let tag = get_small_block(efile, data_block_type::header_tag)?;
+    let mut mac = new_mac(&key);
+    mac.update(&efile.header[0..(offset as usize - 16)]);
+    if mac.verify_slice(&tag).is_err() {
+        return Err(String::from("Header tag check failed."));
+    }
+
+    return Ok(());
+}
+
+/// Computes the MAC of everything read through it.
+///
+/// The key is only known once the header has been parsed, so the bytes are
+/// kept until `set_key`. After that the last bytes read are held back, as
+/// they may turn out to be the `mac` block, which is not covered.
+#[allow(non_camel_case_types)]
+pub(crate) struct mac_reader<R: Read> {
+    ifile: R,
+    mac: Option<hmac_sha3_512>,
+    /// Bytes read but not passed to `mac` yet.
+    pending: Vec<u8>,
+    /// False once the file is known to have no MAC.
+    recording: bool,
+}
+
+impl<R: Read> mac_reader<R> {
+    pub(crate) fn new(ifile: R) -> mac_reader<R> {
+        return mac_reader {
+            ifile,
+            mac: None,
+            pending: Vec::new(),
+            recording: true,
+        };
+    }
+
+    /// Starts the MAC with the key of the unlocked `opt`.
+    pub(crate) fn set_key(&mut self, opt: &encryp_option) {
+        match mac_key(opt) {
+            Some(key) => {
+                self.mac = Some(new_mac(&key));
+                self.cover();
+            }
+            None => {
+                self.recording = false;
+                self.pending = Vec::new();
+            }
+        }
+    }
+
+    fn cover(&mut self) {
+        if let Some(mac) = &mut self.mac {
+            if self.pending.len() > mac_block_len {
+                let end: usize = self.pending.len() - mac_block_len;
+                mac.update(&self.pending[0..end]);
+                self.pending.drain(0..end);
+            }
+        }
+    }
+
+    /// Checks the `mac` block of `efile`, which must have been read through
+    /// `self` to its end. Returns false if the file is not authenticated.
+    pub(crate) fn verify(self, efile: &encrypted_file) -> Result<bool, String> {
+        let mac = match self.mac {
+            Some(mac) => mac,
+            None => return Ok(false),
+        };
+
+        let offset = match efile.data_blocks.get(&data_block_type::mac) {
+            Some(content) => content.offset,
+            None => return Err(String::from("MAC not found.")),
+        };
+        if offset + mac_len != efile.position || self.pending.len() != mac_block_len {
+            return Err(String::from("MAC is not the last data block."));
+        }
+
+        let tag = get_small_block(efile, data_block_type::mac)?;
+        if mac.verify_slice(&tag).is_err() {
+            return Err(String::from("MAC check failed."));
+        }
+
+        return Ok(true);
+    }
+}
+
+impl<R: Read> Read for mac_reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes = self.ifile.read(buf)?;
+        if self.recording {
+            self.pending.extend_from_slice(&buf[0..bytes]);
+            self.cover();
+        }
+        return Ok(bytes);
+    }
+}
+
+/// Checks the MAC of an authenticated file by reading `ifile` once from its
+/// start. `payload_len` is the length of the large block that ends the head
+/// of the file, the ciphertext or the archive payload.
+pub(crate) fn verify_file<R: Read + Seek>(
+    ifile: &mut R,
+    opt: &encryp_option,
+    payload_len: u64,
+) -> Result<(), String> {
+    if mac_key(opt).is_none() {
+        return Ok(());
+    }
+
+    ifile.seek(SeekFrom::Start(0)).map_err(read_error)?;
+
+    let mut authenticated = mac_reader::new(&mut *ifile);
+    let mut efile = parse_encrypted_file(&mut authenticated)?;
+    authenticated.set_key(opt);
+
+    let copied = std::io::copy(
+        &mut (&mut authenticated).take(payload_len),
+        &mut std::io::sink(),
+    )
+    .map_err(read_error)?;
+    if copied != payload_len {
+        return Err(String::from("File is truncated."));
+    }
+    efile.position += payload_len;
+
+    parse_trailing_blocks(&mut authenticated, &mut efile)?;
+    authenticated.verify(&efile)?;
+
+    return Ok(());
+}
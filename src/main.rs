@@ -1,12 +1,31 @@
-use clap::Parser;
-use encryp::{decrypt_file, encryp_file, encryp_option, test_checksum};
+#![allow(clippy::needless_return)]
+#![forbid(unsafe_code)]
+
+use clap::{Parser, Subcommand, ValueEnum};
+use encryp::{
+    archive_entry_kind, audit_info, audit_stream, cipher_algorithm, compression_algorithm,
+    compression_option, decrypt_stream, encryp_option, encryp_option_builder, encryp_stream,
+    inspect_stream, kdf_algorithm, list_archive, pack_archive, padding_policy, progress_hook,
+    progress_info, test_checksum, try_decrypt_file, try_encryp_file, try_upgrade_file,
+    unpack_archive,
+};
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
+use std::io::{IsTerminal, Read, Write};
 use std::path;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Files to encrypt
+    #[command(subcommand)]
+    command: Option<command>,
+
+    /// Files to encrypt, `-` reads from stdin and writes to stdout
     files: Vec<String>,
 
     /// Whether to keep
@@ -14,75 +33,1017 @@ struct Args {
     keep: bool,
 
     /// Whether to cover existing file
-    #[arg(long, default_value_t = false)]
+    #[arg(long, global = true, default_value_t = false)]
     cover_existing_file: bool,
 
-    #[arg(long, default_value_t = 65536)]
+    #[arg(long, global = true, default_value_t = 65536)]
     buffer_size: usize,
 
-    #[arg(short,long, default_value_t = String::from(""))]
+    #[arg(short,long, global = true, default_value_t = String::from(""))]
     password: String,
 
     #[arg(short, long, default_value_t = false)]
     deencrypt: bool,
+
+    /// Process files in directories recursively
+    #[arg(short, long, global = true, default_value_t = false)]
+    recursive: bool,
+
+    /// Output file, only allowed with a single input file. `-` writes to stdout
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Directory to write outputs into, mirroring input directories in recursive mode
+    #[arg(long, global = true)]
+    output_dir: Option<String>,
+
+    /// Suffix appended when encrypting and stripped when decrypting
+    #[arg(long, global = true, default_value_t = String::from(encryp::suffix))]
+    suffix: String,
+
+    /// Compress files before encryption. `auto` uses zstd unless a sample of
+    /// the file does not compress
+    #[arg(long, global = true, value_enum, default_value_t = compress_arg::none)]
+    compress: compress_arg,
+
+    /// Compression level, only used by zstd
+    #[arg(long, global = true, default_value_t = 3)]
+    compress_level: i32,
+
+    /// Pad the ciphertext to hide the length of files
+    #[arg(long, global = true, value_enum, default_value_t = padding_arg::none)]
+    padding: padding_arg,
+
+    /// Bucket size in bytes for `--padding bucket`
+    #[arg(long, global = true, default_value_t = 65536)]
+    padding_bucket: u64,
+
+    /// Encrypt in independent segments of this many bytes so that any part
+    /// of the file can be decrypted without the rest, 0 to disable
+    #[arg(long, global = true, default_value_t = 0)]
+    segment_size: u64,
+
+    /// Threads used to encrypt or decrypt each file. Files encrypted with
+    /// more than one thread are segmented and can't be compressed or padded
+    #[arg(long, global = true, default_value_t = 1)]
+    threads: usize,
+
+    /// Number of files processed at the same time
+    #[arg(short, long, global = true, default_value_t = 1)]
+    jobs: usize,
+
+    /// Key derivation for new files [default: legacy, argon2id for upgrade]
+    #[arg(long, global = true, value_enum)]
+    kdf: Option<kdf_arg>,
+
+    /// Cipher of new files [default: tent, tent-v2 for upgrade]
+    #[arg(long, global = true, value_enum)]
+    cipher: Option<cipher_arg>,
+
+    /// Write new files without MAC and header tag, with an unkeyed checksum
+    /// of the plaintext. Older versions can read them with `--kdf legacy`
+    /// and `--cipher tent`
+    #[arg(long, global = true, default_value_t = false)]
+    legacy_format: bool,
 }
 
-fn main() {
-    let args = Args::parse();
+#[allow(non_camel_case_types)]
+#[derive(Subcommand, Debug)]
+enum command {
+    /// Print the data blocks of encrypted files, no password needed
+    inspect { files: Vec<String> },
+    /// Report reused salts and weak parameters of encrypted files as JSON,
+    /// directories are searched for files ending with --suffix
+    audit {
+        #[arg(required = true)]
+        paths: Vec<String>,
+    },
+    /// Pack files and directories into one encrypted archive
+    pack {
+        archive: String,
+        #[arg(required = true)]
+        inputs: Vec<String>,
+    },
+    /// Extract an archive into --output-dir, or only the given members
+    unpack {
+        archive: String,
+        members: Vec<String>,
+    },
+    /// List the contents of an archive
+    list { archive: String },
+    /// Re-encrypt files in place with the current format and the given
+    /// options, e.g. --kdf. Directories are searched for files ending with
+    /// --suffix in recursive mode
+    upgrade {
+        #[arg(required = true)]
+        files: Vec<String>,
+    },
+}
 
-    //println!("args = {:?}", args);
+#[allow(non_camel_case_types)]
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum padding_arg {
+    none,
+    /// Pad to the next power of two
+    pow2,
+    /// Pad to a multiple of --padding-bucket
+    bucket,
+    /// PADMÉ, at most 12% overhead
+    padme,
+}
 
-    let opt = encryp_option::create(
-        args.keep,
-        args.cover_existing_file,
-        &args.password,
-        args.buffer_size,
-    );
+fn padding_from_args(args: &Args) -> padding_policy {
+    match args.padding {
+        padding_arg::none => return padding_policy::none,
+        padding_arg::pow2 => return padding_policy::power_of_two,
+        padding_arg::bucket => return padding_policy::bucket(args.padding_bucket),
+        padding_arg::padme => return padding_policy::padme,
+    }
+}
 
-    //println!("opt = {:?}", opt);
+#[allow(non_camel_case_types)]
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum compress_arg {
+    none,
+    zstd,
+    lz4,
+    auto,
+}
+
+fn compression_from_args(args: &Args) -> compression_option {
+    let mut ret = compression_option::none();
+    ret.level = args.compress_level;
+
+    match args.compress {
+        compress_arg::none => {}
+        compress_arg::zstd => ret.algorithm = compression_algorithm::zstd,
+        compress_arg::lz4 => ret.algorithm = compression_algorithm::lz4,
+        compress_arg::auto => {
+            ret.algorithm = compression_algorithm::zstd;
+            ret.auto = true;
+        }
+    }
+
+    return ret;
+}
+
+#[allow(non_camel_case_types)]
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum kdf_arg {
+    /// Hash the password as is, readable by older versions
+    legacy,
+    /// Argon2id, much slower to brute force
+    argon2id,
+}
+
+#[allow(non_camel_case_types)]
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum cipher_arg {
+    /// The tent map of the first versions, readable by older versions
+    tent,
+    /// The tent map as intended
+    tent_v2,
+}
+
+/// Template of the options of every file, each gets salts of its own.
+fn options_from_args(args: &Args) -> encryp_option_builder {
+    let (default_kdf, default_cipher) = match args.command {
+        Some(command::upgrade { .. }) => (kdf_arg::argon2id, cipher_arg::tent_v2),
+        _ => (kdf_arg::legacy, cipher_arg::tent),
+    };
+    let kdf = match args.kdf.unwrap_or(default_kdf) {
+        kdf_arg::legacy => kdf_algorithm::legacy,
+        kdf_arg::argon2id => kdf_algorithm::argon2id_default(),
+    };
+    let cipher = match args.cipher.unwrap_or(default_cipher) {
+        cipher_arg::tent => cipher_algorithm::tent_chaos,
+        cipher_arg::tent_v2 => cipher_algorithm::tent_chaos_v2,
+    };
+
+    return encryp_option_builder::new(&args.password)
+        .keep(args.keep)
+        .cover_existing_file(args.cover_existing_file)
+        .buffer_size(args.buffer_size)
+        .kdf(kdf)
+        .cipher(cipher)
+        .authenticated(!args.legacy_format)
+        .compression(compression_from_args(args))
+        .padding(padding_from_args(args))
+        .segment_size(args.segment_size)
+        .threads(args.threads);
+}
+
+/// A source file and the destination it will be written to.
+#[allow(non_camel_case_types)]
+struct job {
+    src: String,
+    dst: String,
+}
+
+fn path_to_string(p: &path::Path) -> Result<String, String> {
+    match p.to_str() {
+        Some(s) => Ok(String::from(s)),
+        None => Err(format!("Path {:?} is not valid UTF-8.", p)),
+    }
+}
+
+/// The options that decide where outputs are written.
+#[allow(non_camel_case_types)]
+struct naming<'a> {
+    suffix: &'a str,
+    deencrypt: bool,
+    output: Option<&'a str>,
+    output_dir: Option<&'a str>,
+}
+
+impl<'a> naming<'a> {
+    fn from_args(args: &'a Args) -> naming<'a> {
+        return naming {
+            suffix: &args.suffix,
+            deencrypt: args.deencrypt,
+            output: args.output.as_deref(),
+            output_dir: args.output_dir.as_deref(),
+        };
+    }
+}
+
+/// Appends or strips the suffix from the last component of `name`.
+fn transform_name(naming: &naming, name: &str) -> Result<String, String> {
+    if !naming.deencrypt {
+        return Ok(String::from(name) + naming.suffix);
+    }
+
+    if naming.suffix.is_empty() || !name.ends_with(naming.suffix) || name == naming.suffix {
+        return Err(format!(
+            "Fatal error : extension of source file {} is not {}.",
+            name, naming.suffix
+        ));
+    }
+
+    return Ok(String::from(&name[0..(name.len() - naming.suffix.len())]));
+}
+
+/// Computes the destination of `src`. `rel` is the path of `src` relative to
+/// the output directory, which keeps the layout of recursively walked inputs.
+fn destination(naming: &naming, src: &path::Path, rel: &path::Path) -> Result<String, String> {
+    if let Some(output) = naming.output {
+        return Ok(String::from(output));
+    }
+
+    let file_name = match rel.file_name() {
+        Some(name) => path_to_string(path::Path::new(name))?,
+        None => return Err(format!("Path {:?} has no file name.", src)),
+    };
+    let file_name = transform_name(naming, &file_name)?;
+
+    let dst = match naming.output_dir {
+        Some(dir) => path::Path::new(dir).join(rel).with_file_name(file_name),
+        None => src.with_file_name(file_name),
+    };
+
+    return path_to_string(&dst);
+}
+
+/// Collects regular files under `dir`, sorted for a stable processing order.
+/// Symbolic links are skipped, they could loop or lead out of `dir`.
+fn walk_dir(dir: &path::Path, files: &mut Vec<path::PathBuf>) -> Result<(), String> {
+    let entries = fs::read_dir(dir).map_err(|e| format!("Failed to read {:?} : {}", dir, e))?;
+
+    let mut paths: Vec<(path::PathBuf, fs::FileType)> = Vec::new();
+    for entry in entries {
+        let entry = entry.map_err(|e| format!("Failed to read {:?} : {}", dir, e))?;
+        let file_type = entry
+            .file_type()
+            .map_err(|e| format!("Failed to read {:?} : {}", entry.path(), e))?;
+        paths.push((entry.path(), file_type));
+    }
+    paths.sort_by(|a, b| a.0.cmp(&b.0));
+
+    for (p, file_type) in paths {
+        if file_type.is_symlink() {
+            continue;
+        }
+        if file_type.is_dir() {
+            walk_dir(&p, files)?;
+        } else {
+            files.push(p);
+        }
+    }
+
+    return Ok(());
+}
+
+/// Files given to `upgrade`, which are replaced by their upgraded versions.
+fn collect_upgrade_jobs(args: &Args, files: &Vec<String>) -> Result<Vec<job>, String> {
+    if args.output_dir.is_some() {
+        return Err(String::from(
+            "Files are upgraded in place, --output-dir can not be used.",
+        ));
+    }
+
+    let mut jobs: Vec<job> = Vec::new();
+
+    for name in files {
+        let src = path::Path::new(name);
+        if !src.is_dir() {
+            jobs.push(job {
+                src: name.clone(),
+                dst: name.clone(),
+            });
+            continue;
+        }
+
+        if !args.recursive {
+            return Err(format!(
+                "{} is a directory, use --recursive to process it.",
+                name
+            ));
+        }
+
+        let mut found: Vec<path::PathBuf> = Vec::new();
+        walk_dir(src, &mut found)?;
+        for file in found {
+            let file_name = path_to_string(&file)?;
+            if file_name.ends_with(&args.suffix) {
+                jobs.push(job {
+                    src: file_name.clone(),
+                    dst: file_name,
+                });
+            }
+        }
+    }
+
+    return Ok(jobs);
+}
+
+fn collect_jobs(args: &Args) -> Result<Vec<job>, String> {
+    if let Some(command::upgrade { files }) = &args.command {
+        return collect_upgrade_jobs(args, files);
+    }
+
+    let naming = naming::from_args(args);
+    let mut jobs: Vec<job> = Vec::new();
+
+    for name in &args.files {
+        if name == "-" {
+            jobs.push(job {
+                src: name.clone(),
+                dst: args.output.clone().unwrap_or(String::from("-")),
+            });
+            continue;
+        }
+
+        let src = path::Path::new(name);
+
+        if !src.is_dir() {
+            let rel = match src.file_name() {
+                Some(file_name) => path::Path::new(file_name),
+                None => src,
+            };
+            jobs.push(job {
+                src: name.clone(),
+                dst: destination(&naming, src, rel)?,
+            });
+            continue;
+        }
+
+        if !args.recursive {
+            return Err(format!(
+                "{} is a directory, use --recursive to process it.",
+                name
+            ));
+        }
+
+        // outputs are mirrored under output_dir starting from the directory's own name
+        let root = match src.parent() {
+            Some(parent) => parent,
+            None => path::Path::new(""),
+        };
+
+        let mut files: Vec<path::PathBuf> = Vec::new();
+        walk_dir(src, &mut files)?;
+
+        for file in files {
+            let file_name = path_to_string(&file)?;
+            // skip outputs of earlier runs and files that can not be decrypted
+            if file_name.ends_with(&args.suffix) != args.deencrypt {
+                continue;
+            }
+
+            let rel = file.strip_prefix(root).unwrap_or(&file);
+            jobs.push(job {
+                dst: destination(&naming, &file, rel)?,
+                src: file_name,
+            });
+        }
+    }
+
+    if args.output.is_some() && jobs.len() != 1 {
+        return Err(String::from(
+            "--output can only be used with exactly one input file.",
+        ));
+    }
+
+    return Ok(jobs);
+}
+
+/// Runs a job that reads from stdin or writes to stdout.
+fn run_pipe_job(job: &job, args: &Args, opt: &encryp_option) -> Result<(), String> {
+    let mut file_size: Option<u64> = None;
+
+    let mut ifile: Box<dyn Read> = if job.src == "-" {
+        Box::new(std::io::stdin().lock())
+    } else {
+        let file =
+            fs::File::open(&job.src).map_err(|e| format!("Failed to open {} : {}", job.src, e))?;
+        file_size = Some(
+            file.metadata()
+                .map_err(|e| format!("Failed to get size of {} : {}", job.src, e))?
+                .len(),
+        );
+        Box::new(file)
+    };
+
+    let mut ofile: Box<dyn Write> = if job.dst == "-" {
+        Box::new(std::io::BufWriter::new(std::io::stdout().lock()))
+    } else {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .truncate(true)
+            .create(true)
+            .create_new(!opt.cover_existing_file)
+            .open(&job.dst)
+            .map_err(|e| format!("Failed to create {} : {}", job.dst, e))?;
+        Box::new(file)
+    };
+
+    let ret = if args.deencrypt {
+        decrypt_stream(&mut ifile, &mut ofile, opt)
+    } else {
+        encryp_stream(&mut ifile, &mut ofile, opt, file_size)
+    };
+    // like in file mode, a failed output is not left behind
+    if ret.is_err() && job.dst != "-" {
+        drop(ofile);
+        let _ = fs::remove_file(&job.dst);
+    }
+    return ret;
+}
+
+/// Encrypts or decrypts a single file and removes the source unless asked to
+/// keep it.
+fn run_job(job: &job, args: &Args, options: &encryp_option_builder) -> Result<(), String> {
+    let opt = &options.build()?;
+
+    let src_filename: &String = &job.src;
+    let dst_filename: &String = &job.dst;
+
+    if let Some(parent) = path::Path::new(dst_filename).parent() {
+        if !parent.as_os_str().is_empty() && fs::create_dir_all(parent).is_err() {
+            return Err(format!("Failed to create directory {:?}.", parent));
+        }
+    }
+
+    if let Some(command::upgrade { .. }) = args.command {
+        // in place, there is no source to remove
+        return try_upgrade_file(src_filename, opt).map_err(|err| err.to_string());
+    }
+
+    if src_filename == "-" || dst_filename == "-" {
+        run_pipe_job(job, args, opt)?;
+    } else if !args.deencrypt {
+        try_encryp_file(src_filename, dst_filename, opt).map_err(|err| err.to_string())?;
+    } else {
+        ////////////////////////
+        // never remove a file that decrypt_file refused to overwrite
+        let dst_kept = path::Path::new(dst_filename).exists() && !opt.cover_existing_file;
+        if let Err(err) = try_decrypt_file(src_filename, dst_filename, opt) {
+            if !dst_kept && path::Path::new(dst_filename).exists() {
+                fs::remove_file(dst_filename).expect("Failed to remove file.");
+            }
+
+            return Err(err.to_string());
+        }
+    }
+
+    if !opt.keep && src_filename != "-" {
+        if let Err(err) = std::fs::remove_file(src_filename) {
+            return Err(format!(
+                "Failed to remove file {:?}, detail : {:?}",
+                src_filename, err
+            ));
+        }
+    }
+    if false {
+        test_checksum(src_filename);
+    }
+
+    return Ok(());
+}
+
+#[allow(non_camel_case_types)]
+struct progress_state {
+    started: Instant,
+    last_draw: Option<Instant>,
+    /// Total size of all sources, 0 if unknown.
+    total: u64,
+    processed: u64,
+    /// Bytes processed of every file reported so far.
+    files: HashMap<String, u64>,
+    current: String,
+    drawn: bool,
+}
+
+/// Progress of all jobs as one line on stderr, with throughput and ETA.
+#[allow(non_camel_case_types)]
+struct progress_bar {
+    state: Mutex<progress_state>,
+}
+
+#[allow(non_upper_case_globals)]
+const progress_interval: Duration = Duration::from_millis(100);
+
+fn format_bytes(bytes: f64) -> String {
+    let units = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes;
+    let mut unit = 0;
+    while value >= 1024.0 && unit + 1 < units.len() {
+        value /= 1024.0;
+        unit += 1;
+    }
+    return format!("{:.1} {}", value, units[unit]);
+}
+
+fn format_duration(seconds: u64) -> String {
+    if seconds >= 3600 {
+        return format!(
+            "{}:{:02}:{:02}",
+            seconds / 3600,
+            seconds / 60 % 60,
+            seconds % 60
+        );
+    }
+    return format!("{:02}:{:02}", seconds / 60, seconds % 60);
+}
 
-    for i in 0..args.files.len() {
-        let src_filename: &String = &args.files[i];
+impl progress_bar {
+    fn new(total: u64) -> progress_bar {
+        return progress_bar {
+            state: Mutex::new(progress_state {
+                started: Instant::now(),
+                last_draw: None,
+                total,
+                processed: 0,
+                files: HashMap::new(),
+                current: String::new(),
+                drawn: false,
+            }),
+        };
+    }
+
+    fn update(&self, info: &progress_info) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return,
+        };
 
-        if !args.deencrypt {
-            let dst_filename: String = src_filename.clone() + encryp::suffix;
+        let file = info.file.unwrap_or("-");
+        let previous = state
+            .files
+            .insert(file.to_string(), info.processed)
+            .unwrap_or(0);
+        state.processed += info.processed.saturating_sub(previous);
+        state.current = file.to_string();
 
-            if !encryp_file(&src_filename, &dst_filename, &opt) {
-                eprintln!(
-                    "Failed to encryp file {} to {}.",
-                    src_filename, dst_filename
-                );
+        let now = Instant::now();
+        if let Some(last_draw) = state.last_draw {
+            if now.duration_since(last_draw) < progress_interval {
                 return;
             }
+        }
+        state.last_draw = Some(now);
+
+        let elapsed = now.duration_since(state.started).as_secs_f64();
+        let throughput = if elapsed > 0.0 {
+            state.processed as f64 / elapsed
         } else {
-            ////////////////////////
-            let mut dst_name: String = String::new();
-            if !decrypt_file(&src_filename, &opt, &mut dst_name) {
-                if path::Path::new(&dst_name).exists() {
-                    fs::remove_file(dst_name).expect("Failed to remove file.");
+            0.0
+        };
+
+        let mut line = String::new();
+        if state.total > 0 {
+            let ratio = (state.processed as f64 / state.total as f64).min(1.0);
+            let filled = (ratio * 30.0) as usize;
+            line += &format!(
+                "[{}{}] {:>3}% ",
+                "#".repeat(filled),
+                ".".repeat(30 - filled),
+                (ratio * 100.0) as u64
+            );
+        }
+        line += &format!(
+            "{} {}/s",
+            format_bytes(state.processed as f64),
+            format_bytes(throughput)
+        );
+        if state.total > 0 && throughput > 0.0 {
+            let left = state.total.saturating_sub(state.processed) as f64 / throughput;
+            line += &format!(" ETA {}", format_duration(left as u64));
+        }
+        line += &format!("  {}", state.current);
+
+        eprint!("\r\x1b[K{}", line);
+        state.drawn = true;
+    }
+
+    /// Removes the bar while `print` writes to the terminal, it is drawn
+    /// again on the next update.
+    fn suspend<F: FnOnce()>(&self, print: F) {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return print(),
+        };
+
+        if state.drawn {
+            eprint!("\r\x1b[K");
+            state.drawn = false;
+        }
+        state.last_draw = None;
+        print();
+    }
+}
+
+/// Prints the outcome of the `index`th of `total` jobs. With several jobs
+/// every line is prefixed with the progress.
+fn report_job(index: usize, total: usize, job: &job, args: &Args, result: &Result<(), String>) {
+    let deencrypt = args.deencrypt;
+    let verb = match args.command {
+        Some(command::upgrade { .. }) => "upgrade",
+        _ if deencrypt => "decrypt",
+        _ => "encryp",
+    };
+    let progress = if total > 1 {
+        format!("[{}/{}] ", index + 1, total)
+    } else {
+        String::new()
+    };
+
+    match result {
+        Ok(()) if total > 1 => eprintln!("{}{} -> {}", progress, job.src, job.dst),
+        // the plaintext itself may be on stdout
+        Ok(()) if deencrypt && job.dst != "-" => println!("success"),
+        Ok(()) => {}
+        Err(err) => eprintln!("{}Failed to {} {} : {}", progress, verb, job.src, err),
+    }
+}
+
+/// Runs `jobs` on `args.jobs` worker threads and returns the number of jobs
+/// that failed. A failed job does not stop the others. Outcomes are printed
+/// in the order of `jobs` as soon as all jobs before them have finished, so
+/// the output does not depend on the scheduling.
+fn run_jobs(jobs: &[job], args: &Args, options: &encryp_option_builder) -> usize {
+    let workers: usize = args.jobs.clamp(1, jobs.len().max(1));
+
+    // the bar is only useful to a human watching a terminal
+    let bar: Option<Arc<progress_bar>> = if std::io::stderr().is_terminal() {
+        let total: u64 = jobs
+            .iter()
+            .filter(|job| job.src != "-")
+            .filter_map(|job| fs::metadata(&job.src).ok())
+            .map(|meta| meta.len())
+            .sum();
+        Some(Arc::new(progress_bar::new(total)))
+    } else {
+        None
+    };
+
+    let mut options: encryp_option_builder = options.clone();
+    if let Some(bar) = &bar {
+        let bar = Arc::clone(bar);
+        options = options.progress(progress_hook::new(move |info| bar.update(info)));
+    }
+    let options = &options;
+
+    let next = AtomicUsize::new(0);
+
+    let mut failed: usize = 0;
+
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::channel::<(usize, Result<(), String>)>();
+
+        for _ in 0..workers {
+            let sender = sender.clone();
+            let next = &next;
+            scope.spawn(move || loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                if index >= jobs.len() {
+                    return;
                 }
 
-                return;
+                let result = run_job(&jobs[index], args, options);
+                if sender.send((index, result)).is_err() {
+                    return;
+                }
+            });
+        }
+        drop(sender);
+
+        let mut pending: BTreeMap<usize, Result<(), String>> = BTreeMap::new();
+        let mut printed: usize = 0;
+
+        for (index, result) in receiver.iter() {
+            pending.insert(index, result);
+
+            while let Some(result) = pending.remove(&printed) {
+                let report = || report_job(printed, jobs.len(), &jobs[printed], args, &result);
+                match &bar {
+                    Some(bar) => bar.suspend(report),
+                    None => report(),
+                }
+                if result.is_err() {
+                    failed += 1;
+                }
+                printed += 1;
+            }
+        }
+    });
+
+    if let Some(bar) = &bar {
+        bar.suspend(|| {});
+    }
+
+    return failed;
+}
+
+fn inspect(files: &Vec<String>) -> ExitCode {
+    let mut ret = ExitCode::SUCCESS;
+
+    for name in files {
+        let blocks = match fs::File::open(name) {
+            Ok(mut file) => inspect_stream(&mut file),
+            Err(err) => Err(format!("Failed to open file : {}", err)),
+        };
+
+        match blocks {
+            Ok(blocks) => {
+                println!("{}", name);
+                for blk in blocks {
+                    println!(
+                        "  {:<24} offset {:<12} length {}",
+                        format!("{:?}", blk.block_type),
+                        blk.offset,
+                        blk.length
+                    );
+                }
+            }
+            Err(err) => {
+                eprintln!("Failed to inspect {} : {}", name, err);
+                ret = ExitCode::FAILURE;
             }
         }
+    }
 
-        if !opt.keep {
-            let ret = std::fs::remove_file(src_filename);
-            if ret.is_err() {
-                eprintln!(
-                    "Failed to remove file {:?}, detail : {:?}",
-                    src_filename,
-                    ret.unwrap_err()
-                );
+    return ret;
+}
 
-                return;
+fn to_hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn kdf_to_json(kdf: &Option<kdf_algorithm>) -> serde_json::Value {
+    match kdf {
+        None => return serde_json::Value::Null,
+        Some(kdf_algorithm::legacy) => return serde_json::json!({ "algorithm": "legacy" }),
+        Some(kdf_algorithm::argon2id {
+            memory,
+            iterations,
+            parallelism,
+        }) => {
+            return serde_json::json!({
+                "algorithm": "argon2id",
+                "memory": memory,
+                "iterations": iterations,
+                "parallelism": parallelism,
+            });
+        }
+    }
+}
+
+/// Problems of a single file, `shared_salt_b` is found by comparing files.
+fn audit_issues(info: &audit_info, shared_salt_b: bool) -> Vec<&'static str> {
+    let mut issues: Vec<&'static str> = Vec::new();
+    if shared_salt_b {
+        issues.push("shared_salt_b");
+    }
+    if info.cipher == cipher_algorithm::tent_chaos {
+        issues.push("legacy_cipher");
+    }
+    if info.kdf.is_none() {
+        issues.push("missing_kdf");
+    }
+    if info.empty_password {
+        issues.push("empty_password");
+    }
+    if !info.standard_head {
+        issues.push("non_standard_head");
+    }
+    return issues;
+}
+
+/// Prints a JSON report of `paths` to stdout. Files that can not be parsed
+/// are reported, paths that can not be read fail the command.
+fn audit(paths: &Vec<String>, suffix: &str) -> ExitCode {
+    let mut files: Vec<String> = Vec::new();
+    for name in paths {
+        let p = path::Path::new(name);
+        if !p.is_dir() {
+            files.push(name.clone());
+            continue;
+        }
+
+        let mut found: Vec<path::PathBuf> = Vec::new();
+        let walked = walk_dir(p, &mut found).and_then(|_| {
+            for file in found {
+                let file = path_to_string(&file)?;
+                if file.ends_with(suffix) {
+                    files.push(file);
+                }
             }
+            return Ok(());
+        });
+        if let Err(err) = walked {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
         }
-        if false {
-            test_checksum(&src_filename);
+    }
+
+    let results: Vec<Result<audit_info, String>> = files
+        .iter()
+        .map(|name| match fs::File::open(name) {
+            Ok(file) => audit_stream(&mut std::io::BufReader::new(file)),
+            Err(err) => Err(format!("Failed to open file : {}", err)),
+        })
+        .collect();
+
+    let mut by_salt_b: BTreeMap<&[u8], Vec<&str>> = BTreeMap::new();
+    for (name, result) in files.iter().zip(&results) {
+        if let Ok(info) = result {
+            by_salt_b.entry(&info.salt_b).or_default().push(name);
         }
     }
 
+    let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut unreadable: usize = 0;
+    let mut report_files: Vec<serde_json::Value> = Vec::new();
+
+    for (name, result) in files.iter().zip(&results) {
+        let info = match result {
+            Ok(info) => info,
+            Err(err) => {
+                unreadable += 1;
+                report_files.push(serde_json::json!({ "path": name, "error": err }));
+                continue;
+            }
+        };
+
+        let issues = audit_issues(info, by_salt_b[info.salt_b.as_slice()].len() > 1);
+        for issue in &issues {
+            *counts.entry(issue).or_default() += 1;
+        }
+
+        report_files.push(serde_json::json!({
+            "path": name,
+            "salt_a": to_hex(&info.salt_a),
+            "salt_b": to_hex(&info.salt_b),
+            "cipher": format!("{:?}", info.cipher),
+            "kdf": kdf_to_json(&info.kdf),
+            "empty_password": info.empty_password,
+            "standard_head": info.standard_head,
+            "issues": issues,
+        }));
+    }
+
+    let shared_salt_b: Vec<serde_json::Value> = by_salt_b
+        .iter()
+        .filter(|(_, names)| names.len() > 1)
+        .map(|(salt_b, names)| serde_json::json!({ "salt_b": to_hex(salt_b), "files": names }))
+        .collect();
+
+    let mut summary = serde_json::json!({
+        "files": files.len(),
+        "unreadable": unreadable,
+    });
+    for issue in [
+        "shared_salt_b",
+        "legacy_cipher",
+        "missing_kdf",
+        "empty_password",
+        "non_standard_head",
+    ] {
+        summary[issue] = serde_json::json!(counts.get(issue).copied().unwrap_or(0));
+    }
+
+    let report = serde_json::json!({
+        "files": report_files,
+        "shared_salt_b": shared_salt_b,
+        "summary": summary,
+    });
+
+    match serde_json::to_string_pretty(&report) {
+        Ok(text) => println!("{}", text),
+        Err(err) => {
+            eprintln!("Failed to write the report : {}", err);
+            return ExitCode::FAILURE;
+        }
+    }
+
+    return ExitCode::SUCCESS;
+}
+
+fn run_archive_command(args: &Args, opt: &encryp_option) -> Result<(), String> {
+    match &args.command {
+        Some(command::pack { archive, inputs }) => {
+            return pack_archive(inputs, archive, opt);
+        }
+        Some(command::unpack { archive, members }) => {
+            let dst_dir = args.output_dir.clone().unwrap_or(String::from("."));
+            return unpack_archive(archive, &dst_dir, members, opt);
+        }
+        Some(command::list { archive }) => {
+            for entry in list_archive(archive, opt)? {
+                match entry.kind {
+                    archive_entry_kind::directory => println!("{:>14}  {}/", "", entry.path),
+                    archive_entry_kind::file => println!("{:>14}  {}", entry.size, entry.path),
+                }
+            }
+            return Ok(());
+        }
+        _ => return Ok(()),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    //println!("args = {:?}", args);
+
+    if let Some(command::inspect { files }) = &args.command {
+        return inspect(files);
+    }
+
+    if let Some(command::audit { paths }) = &args.command {
+        return audit(paths, &args.suffix);
+    }
+
+    if args.output.is_some() && args.output_dir.is_some() {
+        eprintln!("--output and --output-dir can not be used together.");
+        return ExitCode::FAILURE;
+    }
+
+    let options = options_from_args(&args);
+    let opt = match options.build() {
+        Ok(opt) => opt,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let upgrade = matches!(args.command, Some(command::upgrade { .. }));
+
+    if args.command.is_some() && !upgrade {
+        if let Err(err) = run_archive_command(&args, &opt) {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let jobs = match collect_jobs(&args) {
+        Ok(jobs) => jobs,
+        Err(err) => {
+            eprintln!("{}", err);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    //println!("opt = {:?}", opt);
+
+    let failed = run_jobs(&jobs, &args, &options);
+
+    if jobs.len() > 1 {
+        eprintln!(
+            "{} files processed, {} succeeded, {} failed.",
+            jobs.len(),
+            jobs.len() - failed,
+            failed
+        );
+    }
+
+    if failed > 0 {
+        return ExitCode::FAILURE;
+    }
+
     /*/
         let mut tent = encryp::tent_chaos::new(0);
 
@@ -94,5 +1055,122 @@ fn main() {
         //println!("The vector is {:?}", vector);
 
     */
-    return;
+    return ExitCode::SUCCESS;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(suffix: &str, deencrypt: bool) -> naming<'_> {
+        return naming {
+            suffix,
+            deencrypt,
+            output: None,
+            output_dir: None,
+        };
+    }
+
+    #[test]
+    fn suffix_is_appended_and_stripped() {
+        assert_eq!(
+            transform_name(&names(".nya", false), "a.txt").unwrap(),
+            "a.txt.nya"
+        );
+        assert_eq!(
+            transform_name(&names(".nya", true), "a.txt.nya").unwrap(),
+            "a.txt"
+        );
+
+        assert!(transform_name(&names(".nya", true), "a.txt.neko").is_err());
+        assert!(transform_name(&names(".nya", true), ".nya").is_err());
+        assert!(transform_name(&names("", true), "a.txt").is_err());
+    }
+
+    #[test]
+    fn output_replaces_the_destination() {
+        let mut n = names(".neko", true);
+        n.output = Some("plain.txt");
+        let src = path::Path::new("in/a.txt");
+        assert_eq!(
+            destination(&n, src, path::Path::new("a.txt")).unwrap(),
+            "plain.txt"
+        );
+    }
+
+    #[test]
+    fn output_dir_mirrors_nested_directories() {
+        let src = path::Path::new("in/dir/sub/b");
+        let rel = path::Path::new("dir/sub/b");
+
+        let n = names(".neko", false);
+        assert_eq!(destination(&n, src, rel).unwrap(), "in/dir/sub/b.neko");
+
+        let mut n = names(".neko", false);
+        n.output_dir = Some("out");
+        assert_eq!(destination(&n, src, rel).unwrap(), "out/dir/sub/b.neko");
+
+        let mut n = names(".neko", true);
+        n.output_dir = Some("out");
+        let src = path::Path::new("in/dir/sub/b.neko");
+        let rel = path::Path::new("dir/sub/b.neko");
+        assert_eq!(destination(&n, src, rel).unwrap(), "out/dir/sub/b");
+    }
+
+    #[test]
+    fn recursive_jobs_are_mirrored_under_output_dir() {
+        let root = std::env::temp_dir().join(format!("neko-jobs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dir/sub")).unwrap();
+        fs::write(root.join("dir/a"), b"a").unwrap();
+        fs::write(root.join("dir/sub/b"), b"b").unwrap();
+        fs::write(root.join("dir/sub/c.neko"), b"c").unwrap();
+
+        let dir = path_to_string(&root.join("dir")).unwrap();
+        let out = path_to_string(&root.join("out")).unwrap();
+        let args = Args::parse_from(["neko", "-r", "--output-dir", &out, &dir]);
+        let jobs: Vec<(String, String)> = collect_jobs(&args)
+            .unwrap()
+            .into_iter()
+            .map(|j| (j.src, j.dst))
+            .collect();
+        assert_eq!(
+            jobs,
+            [
+                (
+                    path_to_string(&root.join("dir/a")).unwrap(),
+                    path_to_string(&root.join("out/dir/a.neko")).unwrap()
+                ),
+                (
+                    path_to_string(&root.join("dir/sub/b")).unwrap(),
+                    path_to_string(&root.join("out/dir/sub/b.neko")).unwrap()
+                ),
+            ]
+        );
+
+        let args = Args::parse_from(["neko", "-r", "-o", "single", &dir]);
+        assert!(collect_jobs(&args).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn walk_dir_does_not_follow_links() {
+        let root = std::env::temp_dir().join(format!("neko-links-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("dir/sub")).unwrap();
+        fs::create_dir_all(root.join("outside")).unwrap();
+        fs::write(root.join("dir/sub/a"), b"a").unwrap();
+        fs::write(root.join("outside/b"), b"b").unwrap();
+        std::os::unix::fs::symlink("..", root.join("dir/sub/loop")).unwrap();
+        std::os::unix::fs::symlink(root.join("outside"), root.join("dir/escape")).unwrap();
+        std::os::unix::fs::symlink(root.join("outside/b"), root.join("dir/file_link")).unwrap();
+
+        let mut files: Vec<path::PathBuf> = Vec::new();
+        walk_dir(&root.join("dir"), &mut files).unwrap();
+        assert_eq!(files, [root.join("dir/sub/a")]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -0,0 +1,287 @@
+use std::cmp::min;
+use std::io::prelude::*;
+
+/// How the encrypted payload is padded to hide the length of the plaintext.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum padding_policy {
+    none,
+    /// Pad to the next power of two.
+    power_of_two,
+    /// Pad to a multiple of the given number of bytes.
+    bucket(u64),
+    /// PADMÉ, which leaks at most O(log log n) bits with at most 12% overhead.
+    padme,
+}
+
+impl padding_policy {
+    /// The padded length of a framed payload of `len` bytes.
+    pub fn padded_len(&self, len: u64) -> u64 {
+        match self {
+            padding_policy::none => return len,
+            padding_policy::power_of_two => return len.next_power_of_two(),
+            padding_policy::bucket(size) => {
+                let size = (*size).max(1);
+                return len.div_ceil(size) * size;
+            }
+            padding_policy::padme => {
+                if len < 2 {
+                    return len;
+                }
+                let e: u32 = len.ilog2();
+                let s: u32 = e.ilog2() + 1;
+                let last_bits: u32 = e - s;
+                let bit_mask: u64 = (1_u64 << last_bits) - 1;
+                return (len + bit_mask) & !bit_mask;
+            }
+        }
+    }
+
+    /// Content of the `padding` data block: the policy followed by its
+    /// parameter, both as little-endian 64-bit integers.
+    pub(crate) fn encode_block(&self) -> Vec<u8> {
+        let (id, param): (u64, u64) = match self {
+            padding_policy::none => (0, 0),
+            padding_policy::power_of_two => (1, 0),
+            padding_policy::bucket(size) => (2, *size),
+            padding_policy::padme => (3, 0),
+        };
+
+        let mut ret: Vec<u8> = Vec::with_capacity(16);
+        ret.extend_from_slice(id.to_le_bytes().as_slice());
+        ret.extend_from_slice(param.to_le_bytes().as_slice());
+        return ret;
+    }
+
+    pub(crate) fn decode_block(data: &[u8]) -> Result<padding_policy, String> {
+        if data.len() != 16 {
+            return Err(format!("Invalid padding block of {} bytes.", data.len()));
+        }
+
+        let mut id = [0_u8; 8];
+        id.copy_from_slice(&data[0..8]);
+        let mut param = [0_u8; 8];
+        param.copy_from_slice(&data[8..16]);
+
+        match u64::from_le_bytes(id) {
+            0 => return Ok(padding_policy::none),
+            1 => return Ok(padding_policy::power_of_two),
+            2 => return Ok(padding_policy::bucket(u64::from_le_bytes(param))),
+            3 => return Ok(padding_policy::padme),
+            other => return Err(format!("Unknown padding policy {}.", other)),
+        }
+    }
+}
+
+/// Frames everything written to it so that the end of the real data can be
+/// found after padding.
+///
+/// The framed payload is a sequence of records, each prefixed with its length
+/// as a little-endian u64, then an empty record followed by the total length
+/// of the real data, then zeros up to the padded length. The framing is
+/// encrypted together with the data, so only the padded length is visible.
+/// With `padding_policy::none` everything is passed through unframed.
+#[allow(non_camel_case_types)]
+pub(crate) struct padder<W: Write> {
+    ofile: W,
+    policy: padding_policy,
+    buffer: Vec<u8>,
+    filled: usize,
+    real_len: u64,
+    framed_len: u64,
+}
+
+impl<W: Write> padder<W> {
+    pub(crate) fn new(policy: padding_policy, record_size: usize, ofile: W) -> padder<W> {
+        let buffer_len = if policy == padding_policy::none {
+            0
+        } else {
+            record_size.max(1)
+        };
+
+        return padder {
+            ofile,
+            policy,
+            buffer: vec![0; buffer_len],
+            filled: 0,
+            real_len: 0,
+            framed_len: 0,
+        };
+    }
+
+    fn write_record(&mut self) -> std::io::Result<()> {
+        self.ofile
+            .write_all((self.filled as u64).to_le_bytes().as_slice())?;
+        self.ofile.write_all(&self.buffer[0..self.filled])?;
+
+        self.framed_len += 8 + self.filled as u64;
+        self.real_len += self.filled as u64;
+        self.filled = 0;
+        return Ok(());
+    }
+
+    /// Writes the last record, the terminator and the padding.
+    pub(crate) fn finish(mut self) -> std::io::Result<W> {
+        if self.policy == padding_policy::none {
+            return Ok(self.ofile);
+        }
+
+        if self.filled > 0 {
+            self.write_record()?;
+        }
+
+        self.ofile.write_all(0_u64.to_le_bytes().as_slice())?;
+        self.ofile
+            .write_all(self.real_len.to_le_bytes().as_slice())?;
+        self.framed_len += 16;
+
+        let mut padding_left: u64 = self.policy.padded_len(self.framed_len) - self.framed_len;
+
+        self.buffer.fill(0);
+        while padding_left > 0 {
+            let bytes: usize = min(self.buffer.len() as u64, padding_left) as usize;
+            self.ofile.write_all(&self.buffer[0..bytes])?;
+            padding_left -= bytes as u64;
+        }
+
+        return Ok(self.ofile);
+    }
+}
+
+impl<W: Write> Write for padder<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if self.policy == padding_policy::none {
+            return self.ofile.write(buf);
+        }
+
+        let bytes: usize = min(self.buffer.len() - self.filled, buf.len());
+        self.buffer[self.filled..(self.filled + bytes)].copy_from_slice(&buf[0..bytes]);
+        self.filled += bytes;
+
+        if self.filled == self.buffer.len() {
+            self.write_record()?;
+        }
+
+        return Ok(bytes);
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return self.ofile.flush();
+    }
+}
+
+/// Reads the real data out of a payload framed by `padder`, stopping at the
+/// terminator. The padding behind it is left for the caller to skip.
+#[allow(non_camel_case_types)]
+pub(crate) struct unpadder<R: Read> {
+    ifile: R,
+    framed: bool,
+    record_left: u64,
+    real_len: u64,
+    finished: bool,
+}
+
+impl<R: Read> unpadder<R> {
+    pub(crate) fn new(policy: padding_policy, ifile: R) -> unpadder<R> {
+        return unpadder {
+            ifile,
+            framed: policy != padding_policy::none,
+            record_left: 0,
+            real_len: 0,
+            finished: false,
+        };
+    }
+
+    fn read_u64(&mut self) -> std::io::Result<u64> {
+        let mut bytes = [0_u8; 8];
+        self.ifile.read_exact(&mut bytes)?;
+        return Ok(u64::from_le_bytes(bytes));
+    }
+}
+
+impl<R: Read> Read for unpadder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if !self.framed {
+            return self.ifile.read(buf);
+        }
+
+        if self.finished || buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.record_left == 0 {
+            self.record_left = self.read_u64()?;
+
+            if self.record_left == 0 {
+                let real_len = self.read_u64()?;
+                if real_len != self.real_len {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "Length of padded data mismatch.",
+                    ));
+                }
+                self.finished = true;
+                return Ok(0);
+            }
+        }
+
+        let bytes: usize = min(self.record_left, buf.len() as u64) as usize;
+        let bytes = self.ifile.read(&mut buf[0..bytes])?;
+        if bytes == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Padded data is truncated.",
+            ));
+        }
+
+        self.record_left -= bytes as u64;
+        self.real_len += bytes as u64;
+
+        return Ok(bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_len_is_never_shorter() {
+        let policies = [
+            padding_policy::none,
+            padding_policy::power_of_two,
+            padding_policy::bucket(100),
+            padding_policy::padme,
+        ];
+        for policy in policies {
+            for len in (0..5000).chain([1 << 20, (1 << 20) + 1, u32::MAX as u64]) {
+                assert!(policy.padded_len(len) >= len, "{:?} {}", policy, len);
+            }
+        }
+
+        assert_eq!(padding_policy::bucket(100).padded_len(101), 200);
+        assert_eq!(padding_policy::power_of_two.padded_len(1000), 1024);
+        assert_eq!(padding_policy::padme.padded_len(100000), 100352);
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let policies = [
+            padding_policy::none,
+            padding_policy::power_of_two,
+            padding_policy::bucket(4096),
+            padding_policy::padme,
+        ];
+        for policy in policies {
+            assert_eq!(
+                padding_policy::decode_block(&policy.encode_block()),
+                Ok(policy)
+            );
+        }
+
+        assert!(padding_policy::decode_block(&[0; 15]).is_err());
+        let mut unknown = padding_policy::none.encode_block();
+        unknown[0] = 9;
+        assert!(padding_policy::decode_block(&unknown).is_err());
+    }
+}
@@ -0,0 +1,305 @@
+use std::collections::BTreeMap;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::{
+    check_segmented, compression_algorithm, data_block_type, decrypt_stream, derive_key,
+    encryp_option, encryp_stream, get_ciphertext_info, get_compression, get_padding,
+    get_segment_size, keystream, mac, parse_encrypted_file, parse_trailing_blocks, progress,
+    read_error, read_full, tree_hash, unlock, verify_segment_hashes, write_data_block,
+    write_data_block_head, write_error, write_header,
+};
+
+/// Segment size used in parallel mode when `encryp_option::segment_size` is 0.
+#[allow(non_upper_case_globals)]
+const default_segment_size: u64 = 1 << 20;
+
+/// A segment of plaintext or ciphertext on its way through the pipeline.
+#[allow(non_camel_case_types)]
+struct segment {
+    index: u64,
+    data: Vec<u8>,
+}
+
+/// A segment after a worker has hashed and encrypted or decrypted it.
+#[allow(non_camel_case_types)]
+struct processed_segment {
+    index: u64,
+    data: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+/// Segment size to encrypt with in parallel, `None` if the options require
+/// the serial pipeline.
+fn parallel_segment_size(opt: &encryp_option) -> Option<u64> {
+    if opt.threads <= 1
+        || opt.compression.algorithm != compression_algorithm::none
+        || opt.padding != crate::padding_policy::none
+    {
+        return None;
+    }
+
+    if opt.segment_size != 0 {
+        return Some(opt.segment_size);
+    }
+    return Some(default_segment_size);
+}
+
+/// Reads `ifile` in segments of `segment_size` bytes, at most `length` bytes
+/// in total, and hands them to the workers. Returns the number of bytes read.
+fn read_segments<R: Read>(
+    ifile: &mut R,
+    segment_size: u64,
+    length: u64,
+    work: mpsc::SyncSender<segment>,
+) -> Result<u64, String> {
+    let mut total_read: u64 = 0;
+    let mut index: u64 = 0;
+
+    while total_read < length {
+        let bytes: usize = segment_size.min(length - total_read) as usize;
+
+        let mut data: Vec<u8> = vec![0; bytes];
+        let len = read_full(ifile, &mut data).map_err(read_error)?;
+        if len == 0 {
+            break;
+        }
+        data.truncate(len);
+        total_read += len as u64;
+
+        if work.send(segment { index, data }).is_err() {
+            // a worker or the writer failed, its error is reported instead
+            break;
+        }
+        index += 1;
+
+        if len < bytes {
+            break;
+        }
+    }
+
+    return Ok(total_read);
+}
+
+/// Encrypts or decrypts the segments handed out by the reader. The plaintext
+/// is hashed before encryption or after decryption, keyed by `key` if given.
+fn process_segments(
+    opt: &encryp_option,
+    key: Option<&[u8]>,
+    segment_size: u64,
+    encrypt: bool,
+    work: &Mutex<mpsc::Receiver<segment>>,
+    done: mpsc::Sender<processed_segment>,
+) {
+    loop {
+        let received = match work.lock() {
+            Ok(receiver) => receiver.recv(),
+            Err(_) => return,
+        };
+        let mut segment = match received {
+            Ok(segment) => segment,
+            Err(_) => return,
+        };
+
+        let mut hash: Vec<u8> = Vec::new();
+        if encrypt {
+            hash = mac::segment_digest::digest(key, &segment.data);
+        }
+
+        let mut keys = keystream::segmented_from(opt, segment_size, segment.index);
+        keys.apply(&mut segment.data);
+
+        if !encrypt {
+            hash = mac::segment_digest::digest(key, &segment.data);
+        }
+        let processed = processed_segment {
+            index: segment.index,
+            data: segment.data,
+            hash,
+        };
+
+        if done.send(processed).is_err() {
+            return;
+        }
+    }
+}
+
+/// Runs the reader thread and `opt.threads` workers, and writes the processed
+/// segments to `ofile` in order on the calling thread. Returns the number of
+/// bytes read and the concatenated hashes of the plaintext segments.
+fn run_pipeline<R: Read + Send, W: Write>(
+    ifile: &mut R,
+    ofile: &mut W,
+    opt: &encryp_option,
+    segment_size: u64,
+    length: u64,
+    encrypt: bool,
+) -> Result<(u64, Vec<u8>), String> {
+    // bounds the number of segments held in memory
+    let capacity: usize = opt.threads * 2;
+    let key = mac::mac_key(opt);
+
+    return thread::scope(|scope| {
+        let (work_sender, work_receiver) = mpsc::sync_channel::<segment>(capacity);
+        let (done_sender, done_receiver) = mpsc::channel();
+        let work_receiver = Arc::new(Mutex::new(work_receiver));
+
+        let reader = scope.spawn(move || read_segments(ifile, segment_size, length, work_sender));
+
+        for _ in 0..opt.threads {
+            let work_receiver = Arc::clone(&work_receiver);
+            let done_sender = done_sender.clone();
+            let key = key.as_deref();
+            scope.spawn(move || {
+                process_segments(opt, key, segment_size, encrypt, &work_receiver, done_sender)
+            });
+        }
+        drop(done_sender);
+
+        let mut pending: BTreeMap<u64, processed_segment> = BTreeMap::new();
+        let mut hashes: Vec<u8> = Vec::new();
+        let mut next: u64 = 0;
+
+        for processed in done_receiver.iter() {
+            pending.insert(processed.index, processed);
+
+            while let Some(processed) = pending.remove(&next) {
+                ofile.write_all(&processed.data).map_err(write_error)?;
+                hashes.extend_from_slice(&processed.hash);
+                next += 1;
+            }
+        }
+
+        let total_read = match reader.join() {
+            Ok(result) => result?,
+            Err(_) => return Err(String::from("Reader thread panicked.")),
+        };
+
+        if next != total_read.div_ceil(segment_size) {
+            return Err(String::from("A worker thread stopped early."));
+        }
+
+        return Ok((total_read, hashes));
+    });
+}
+
+/// Encrypts `ifile` into `ofile` on `opt.threads` threads.
+///
+/// The file is split into segments with their own keystreams, see
+/// `encryp_option::segment_size`, which are hashed and encrypted by
+/// independent workers. Instead of a hash of the whole plaintext, which can
+/// only be computed serially, the file is verified by `segment_hashes` and
+/// their hash in `sha3_512_tree`, or by the MAC if it has one. Falls back to `encryp_stream` if the file
+/// is compressed or padded.
+pub fn encryp_parallel<R: Read + Send, W: Write>(
+    ifile: &mut R,
+    ofile: &mut W,
+    opt: &encryp_option,
+    file_size: u64,
+) -> Result<(), String> {
+    opt.validate()?;
+
+    let segment_size: u64 = match parallel_segment_size(opt) {
+        Some(size) => size,
+        None => return encryp_stream(ifile, ofile, opt, Some(file_size)),
+    };
+
+    let mut opt: encryp_option = derive_key(opt)?;
+    opt.segment_size = segment_size;
+    check_segmented(&opt)?;
+
+    let ofile = &mut mac::mac_writer::new(ofile, &opt);
+
+    write_header(ofile, &opt)?;
+
+    write_data_block(
+        ofile,
+        data_block_type::segment_size,
+        segment_size.to_le_bytes().as_slice(),
+    )
+    .map_err(write_error)?;
+
+    ofile.write_header_tag().map_err(write_error)?;
+
+    write_data_block_head(ofile, data_block_type::ciphertext, file_size).map_err(write_error)?;
+
+    let ifile = &mut progress::progress_reader::new(ifile, &opt, Some(file_size));
+
+    let (total_read, hashes) = run_pipeline(ifile, ofile, &opt, segment_size, file_size, true)?;
+
+    let mut extra = [0_u8; 1];
+    if total_read != file_size || read_full(ifile, &mut extra).map_err(read_error)? != 0 {
+        return Err(String::from("Source changed while encrypting."));
+    }
+
+    write_data_block(ofile, data_block_type::segment_hashes, &hashes).map_err(write_error)?;
+    if !ofile.is_keyed() {
+        write_data_block(ofile, data_block_type::sha3_512_tree, &tree_hash(&hashes))
+            .map_err(write_error)?;
+    }
+
+    ofile.finish().map_err(write_error)?;
+    ofile.flush().map_err(write_error)?;
+
+    return Ok(());
+}
+
+/// Decrypts `ifile` into `ofile` on `opt.threads` threads.
+///
+/// Only segmented files with a ciphertext of known length are decrypted in
+/// parallel, others are decrypted by `decrypt_stream`. As there, the
+/// plaintext is written before it can be verified.
+pub fn decrypt_parallel<R: Read + Seek + Send, W: Write>(
+    ifile: &mut R,
+    ofile: &mut W,
+    __opt: &encryp_option,
+) -> Result<(), String> {
+    __opt.validate()?;
+
+    let mut authenticated = mac::mac_reader::new(&mut *ifile);
+
+    let mut efile = parse_encrypted_file(&mut authenticated)?;
+
+    let opt = unlock(&efile, __opt)?;
+
+    let cipher_info = get_ciphertext_info(&efile)?;
+    let segment_size = get_segment_size(&efile)?;
+
+    let serial: bool = opt.threads <= 1
+        || segment_size == 0
+        || cipher_info.length.is_none()
+        || get_compression(&efile)? != compression_algorithm::none
+        || get_padding(&efile)? != crate::padding_policy::none;
+
+    if serial {
+        drop(authenticated);
+        ifile.seek(SeekFrom::Start(0)).map_err(read_error)?;
+        return decrypt_stream(ifile, ofile, &opt);
+    }
+
+    authenticated.set_key(&opt);
+
+    let length: u64 = cipher_info.length.unwrap_or(0);
+
+    let ifile = &mut progress::progress_reader::new(&mut authenticated, &opt, Some(length));
+
+    let (total_read, hashes) = run_pipeline(ifile, ofile, &opt, segment_size, length, false)?;
+    if total_read != length {
+        return Err(String::from("Ciphertext is truncated."));
+    }
+    efile.position += total_read;
+
+    // past the ciphertext, which is all the progress counts
+    parse_trailing_blocks(&mut authenticated, &mut efile)?;
+
+    // legacy files are verified by their segment hashes alone
+    authenticated.verify(&efile)?;
+    verify_segment_hashes(&efile, &hashes)?;
+
+    ofile.flush().map_err(write_error)?;
+
+    return Ok(());
+}
@@ -0,0 +1,109 @@
+use std::io::prelude::*;
+use std::sync::Arc;
+
+use crate::encryp_option;
+
+/// Progress of a single encryption or decryption.
+#[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy)]
+pub struct progress_info<'a> {
+    /// Source file, `None` when the library was handed a stream.
+    pub file: Option<&'a str>,
+    /// Bytes read from the source so far.
+    pub processed: u64,
+    /// Bytes to read in total, `None` if unknown, e.g. for pipes. When
+    /// decrypting this is the length of the ciphertext.
+    pub total: Option<u64>,
+}
+
+#[allow(non_camel_case_types)]
+pub type progress_callback = dyn Fn(&progress_info) + Send + Sync;
+
+/// Callback called after every buffer read from the source. Does nothing
+/// unless created by `progress_hook::new`.
+#[allow(non_camel_case_types)]
+#[derive(Clone, Default)]
+pub struct progress_hook {
+    callback: Option<Arc<progress_callback>>,
+    file: Option<Arc<str>>,
+}
+
+impl progress_hook {
+    pub fn new<F: Fn(&progress_info) + Send + Sync + 'static>(callback: F) -> progress_hook {
+        return progress_hook {
+            callback: Some(Arc::new(callback)),
+            file: None,
+        };
+    }
+
+    pub fn none() -> progress_hook {
+        return progress_hook::default();
+    }
+
+    /// The same callback, reporting `file` as the source.
+    pub(crate) fn for_file(&self, file: &str) -> progress_hook {
+        return progress_hook {
+            callback: self.callback.clone(),
+            file: Some(Arc::from(file)),
+        };
+    }
+
+    pub(crate) fn report(&self, processed: u64, total: Option<u64>) {
+        if let Some(callback) = &self.callback {
+            callback(&progress_info {
+                file: self.file.as_deref(),
+                processed,
+                total,
+            });
+        }
+    }
+}
+
+impl std::fmt::Debug for progress_hook {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f
+            .debug_struct("progress_hook")
+            .field("callback", &self.callback.is_some())
+            .field("file", &self.file)
+            .finish();
+    }
+}
+
+/// Reports every read through it to `opt.progress`, and fails once
+/// `opt.cancel` is cancelled.
+#[allow(non_camel_case_types)]
+pub(crate) struct progress_reader<'a, R: Read> {
+    ifile: R,
+    opt: &'a encryp_option,
+    processed: u64,
+    total: Option<u64>,
+}
+
+impl<'a, R: Read> progress_reader<'a, R> {
+    pub(crate) fn new(
+        ifile: R,
+        opt: &'a encryp_option,
+        total: Option<u64>,
+    ) -> progress_reader<'a, R> {
+        opt.progress.report(0, total);
+        return progress_reader {
+            ifile,
+            opt,
+            processed: 0,
+            total,
+        };
+    }
+}
+
+impl<R: Read> Read for progress_reader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.opt.cancel.check()?;
+
+        let bytes = self.ifile.read(buf)?;
+        if bytes > 0 {
+            self.processed += bytes as u64;
+            self.opt.progress.report(self.processed, self.total);
+        }
+        return Ok(bytes);
+    }
+}
@@ -0,0 +1,199 @@
+use std::cmp::min;
+use std::fs;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use crate::{
+    data_block_type, encryp_option, get_ciphertext_info, get_segment_size, get_small_block,
+    keystream, mac, parse_encrypted_file, parse_trailing_blocks, read_error, read_full, unlock,
+    verify_segment_hashes,
+};
+
+/// Decrypts a segmented file at random offsets.
+///
+/// Only the segments that are read are decrypted, each one is checked against
+/// its own hash before any of its bytes are returned.
+/// The hashes alone do not show that segments were cut from the end, so in
+/// authenticated files `new` reads the whole file once to check its MAC.
+/// Files in the legacy format are not authenticated and are not protected
+/// against tampering.
+#[allow(non_camel_case_types)]
+pub struct decrypt_reader<R: Read + Seek> {
+    ifile: R,
+    opt: encryp_option,
+    ciphertext_offset: u64,
+    length: u64,
+    segment_size: u64,
+    segment_hashes: Vec<u8>,
+    /// Key of the segment hashes, see `mac::mac_key`.
+    key: Option<Vec<u8>>,
+    /// Index and plaintext of the segment decrypted last.
+    segment: Option<(u64, Vec<u8>)>,
+    position: u64,
+}
+
+impl<R: Read + Seek> decrypt_reader<R> {
+    pub fn new(mut ifile: R, opt: &encryp_option) -> Result<decrypt_reader<R>, String> {
+        opt.validate()?;
+        ifile.seek(SeekFrom::Start(0)).map_err(read_error)?;
+
+        let mut efile = parse_encrypted_file(&mut ifile)?;
+
+        let opt = unlock(&efile, opt)?;
+
+        let cipher_info = get_ciphertext_info(&efile)?;
+        let length = match cipher_info.length {
+            Some(length) => length,
+            None => {
+                return Err(String::from(
+                    "Random access requires a ciphertext of known length.",
+                ));
+            }
+        };
+
+        let segment_size = get_segment_size(&efile)?;
+        if segment_size == 0 {
+            return Err(String::from("File is not segmented."));
+        }
+
+        let end = match cipher_info.offset.checked_add(length) {
+            Some(end) => end,
+            None => return Err(String::from("Invalid ciphertext length.")),
+        };
+        ifile.seek(SeekFrom::Start(end)).map_err(read_error)?;
+        efile.position = end;
+        parse_trailing_blocks(&mut ifile, &mut efile)?;
+
+        let segment_hashes = get_small_block(&efile, data_block_type::segment_hashes)?;
+        if Some(segment_hashes.len() as u64) != length.div_ceil(segment_size).checked_mul(64) {
+            return Err(String::from("Segment hashes do not match the ciphertext."));
+        }
+        verify_segment_hashes(&efile, &segment_hashes)?;
+        mac::verify_file(&mut ifile, &opt, length)?;
+
+        return Ok(decrypt_reader {
+            ifile,
+            key: mac::mac_key(&opt),
+            opt,
+            ciphertext_offset: cipher_info.offset,
+            length,
+            segment_size,
+            segment_hashes,
+            segment: None,
+            position: 0,
+        });
+    }
+
+    /// Length of the plaintext.
+    pub fn len(&self) -> u64 {
+        return self.length;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.length == 0;
+    }
+
+    fn load_segment(&mut self, index: u64) -> std::io::Result<()> {
+        if let Some((loaded, _)) = &self.segment {
+            if *loaded == index {
+                return Ok(());
+            }
+        }
+
+        let begin: u64 = index * self.segment_size;
+        let len: usize = min(self.segment_size, self.length - begin) as usize;
+
+        self.ifile
+            .seek(SeekFrom::Start(self.ciphertext_offset + begin))?;
+
+        let mut buffer: Vec<u8> = vec![0; len];
+        if read_full(&mut self.ifile, &mut buffer)? != len {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "Ciphertext is truncated.",
+            ));
+        }
+
+        let mut keys = keystream::segmented_from(&self.opt, self.segment_size, index);
+        keys.apply(&mut buffer);
+
+        let hash_begin: usize = index as usize * 64;
+        if mac::segment_digest::digest(self.key.as_deref(), &buffer)
+            != self.segment_hashes[hash_begin..(hash_begin + 64)]
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("Checksum of segment {} failed.", index),
+            ));
+        }
+
+        self.segment = Some((index, buffer));
+        return Ok(());
+    }
+}
+
+impl<R: Read + Seek> Read for decrypt_reader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.position >= self.length || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index: u64 = self.position / self.segment_size;
+        self.load_segment(index)?;
+
+        let segment = match &self.segment {
+            Some((_, segment)) => segment,
+            None => return Ok(0),
+        };
+
+        let begin: usize = (self.position - index * self.segment_size) as usize;
+        let bytes: usize = min(segment.len() - begin, buf.len());
+        buf[0..bytes].copy_from_slice(&segment[begin..(begin + bytes)]);
+        self.position += bytes as u64;
+
+        return Ok(bytes);
+    }
+}
+
+impl<R: Read + Seek> Seek for decrypt_reader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let position: Option<u64> = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => self.length.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        };
+
+        match position {
+            Some(position) => {
+                self.position = position;
+                return Ok(position);
+            }
+            None => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Seek to a negative position.",
+                ));
+            }
+        }
+    }
+}
+
+/// Decrypts `len` bytes at `offset` of the plaintext of the segmented file
+/// `src_name`. The result is shorter if it reaches the end of the file.
+pub fn decrypt_range(
+    src_name: &String,
+    offset: u64,
+    len: u64,
+    opt: &encryp_option,
+) -> Result<Vec<u8>, String> {
+    let ifile =
+        fs::File::open(src_name).map_err(|e| format!("Failed to open {} : {}", src_name, e))?;
+
+    let mut reader = decrypt_reader::new(std::io::BufReader::new(ifile), opt)?;
+    reader.seek(SeekFrom::Start(offset)).map_err(read_error)?;
+
+    let mut ret: Vec<u8> = Vec::new();
+    reader.take(len).read_to_end(&mut ret).map_err(read_error)?;
+
+    return Ok(ret);
+}
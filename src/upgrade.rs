@@ -0,0 +1,203 @@
+use std::fs;
+use std::io::prelude::*;
+use std::sync::mpsc;
+use std::thread;
+
+use crate::{
+    data_block_type, decrypt_stream, encryp_error, encryp_option, encryp_parallel, encryp_stream,
+    get_ciphertext_info, get_compression, get_padding, parse_encrypted_file, progress_hook,
+    read_error, write_error,
+};
+
+/// Number of buffers in flight between decryption and encryption.
+#[allow(non_upper_case_globals)]
+const pipe_capacity: usize = 4;
+
+/// Plaintext from the decrypting thread. An error of the decryption is
+/// passed on, so that the encryption fails instead of seeing the end early.
+#[allow(non_camel_case_types)]
+struct pipe_reader {
+    receiver: mpsc::Receiver<Result<Vec<u8>, String>>,
+    chunk: Vec<u8>,
+    begin: usize,
+}
+
+impl Read for pipe_reader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.begin == self.chunk.len() {
+            match self.receiver.recv() {
+                Err(_) => return Ok(0),
+                Ok(Err(err)) => return Err(std::io::Error::other(err)),
+                Ok(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.begin = 0;
+                }
+            }
+        }
+
+        let bytes: usize = std::cmp::min(self.chunk.len() - self.begin, buf.len());
+        buf[0..bytes].copy_from_slice(&self.chunk[self.begin..(self.begin + bytes)]);
+        self.begin += bytes;
+
+        return Ok(bytes);
+    }
+}
+
+#[allow(non_camel_case_types)]
+struct pipe_writer {
+    sender: mpsc::SyncSender<Result<Vec<u8>, String>>,
+}
+
+impl Write for pipe_writer {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        if self.sender.send(Ok(buf.to_vec())).is_err() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "Encryption stopped.",
+            ));
+        }
+        return Ok(buf.len());
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        return Ok(());
+    }
+}
+
+/// Decrypts `ifile` with the password of `opt` and encrypts the plaintext
+/// into `ofile` with the settings of `opt`. The plaintext only passes through
+/// memory.
+///
+/// `file_size` is the length of the plaintext if known, see `encryp_stream`.
+/// As the checksum of `ifile` is only verified at its end, `ofile` must be
+/// discarded unless this returns `Ok`.
+pub fn upgrade_stream<R: Read + Send, W: Write>(
+    ifile: &mut R,
+    ofile: &mut W,
+    opt: &encryp_option,
+    file_size: Option<u64>,
+) -> Result<(), String> {
+    // progress is reported by the decryption, which sees the whole source
+    let mut new_opt: encryp_option = opt.clone();
+    new_opt.progress = progress_hook::none();
+
+    let (sender, receiver) = mpsc::sync_channel::<Result<Vec<u8>, String>>(pipe_capacity);
+
+    return thread::scope(|scope| {
+        let decryption = scope.spawn(move || {
+            let mut plaintext = pipe_writer { sender };
+            let result = decrypt_stream(ifile, &mut plaintext, opt);
+            if let Err(err) = &result {
+                let _ = plaintext.sender.send(Err(err.clone()));
+            }
+            return result;
+        });
+
+        let mut plaintext = pipe_reader {
+            receiver,
+            chunk: Vec::new(),
+            begin: 0,
+        };
+        let encryption = match file_size {
+            Some(file_size) if new_opt.threads > 1 => {
+                encryp_parallel(&mut plaintext, ofile, &new_opt, file_size)
+            }
+            _ => encryp_stream(&mut plaintext, ofile, &new_opt, file_size),
+        };
+        // unblocks the decryption if the encryption stopped early
+        drop(plaintext);
+
+        match decryption.join() {
+            Ok(result) => result?,
+            Err(_) => return Err(String::from("Decryption thread panicked.")),
+        }
+        return encryption;
+    });
+}
+
+/// Length of the plaintext of `ifile`, if its ciphertext tells it.
+fn plaintext_len<R: Read>(ifile: &mut R) -> Result<Option<u64>, String> {
+    let efile = parse_encrypted_file(ifile)?;
+
+    if efile
+        .data_blocks
+        .contains_key(&data_block_type::archive_index)
+    {
+        return Err(String::from("Archives can not be upgraded."));
+    }
+
+    let cipher_info = get_ciphertext_info(&efile)?;
+    if get_compression(&efile)? != crate::compression_algorithm::none
+        || get_padding(&efile)? != crate::padding_policy::none
+    {
+        return Ok(None);
+    }
+    return Ok(cipher_info.length);
+}
+
+fn upgrade_file_to(
+    src_name: &str,
+    tmp_name: &str,
+    ofile: &mut fs::File,
+    opt: &encryp_option,
+) -> Result<(), String> {
+    let mut ifile =
+        fs::File::open(src_name).map_err(|e| format!("Failed to open {} : {}", src_name, e))?;
+
+    let file_size = plaintext_len(&mut ifile)?;
+    ifile
+        .seek(std::io::SeekFrom::Start(0))
+        .map_err(read_error)?;
+
+    let mut opt: encryp_option = opt.clone();
+    opt.progress = opt.progress.for_file(src_name);
+
+    upgrade_stream(&mut ifile, ofile, &opt, file_size)?;
+
+    ofile.sync_all().map_err(write_error)?;
+
+    if let Ok(meta) = ifile.metadata() {
+        let _ = fs::set_permissions(tmp_name, meta.permissions());
+    }
+
+    return Ok(());
+}
+
+/// Re-encrypts the encrypted file `src_name` in place with the settings of
+/// `opt`, which must have the password of the file.
+///
+/// The new file is written next to it and replaces it only once the old
+/// checksum has been verified, so `src_name` is never left half written.
+pub fn try_upgrade_file(src_name: &String, opt: &encryp_option) -> Result<(), encryp_error> {
+    opt.validate().map_err(encryp_error::failed)?;
+
+    let tmp_name = format!("{}.{:016x}.tmp", src_name, rand::random::<u64>());
+
+    let mut ofile = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&tmp_name)
+        .map_err(|e| encryp_error::failed(format!("Failed to create {} : {}", tmp_name, e)))?;
+
+    let mut result = upgrade_file_to(src_name, &tmp_name, &mut ofile, opt);
+    drop(ofile);
+
+    if result.is_ok() {
+        result = fs::rename(&tmp_name, src_name)
+            .map_err(|e| format!("Failed to replace {} : {}", src_name, e));
+    }
+
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp_name);
+        if opt.cancel.is_cancelled() {
+            return Err(encryp_error::cancelled);
+        }
+        return Err(encryp_error::failed(err));
+    }
+
+    return Ok(());
+}
@@ -0,0 +1,142 @@
+#![cfg(feature = "async")]
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use common::*;
+use encryp::{
+    async_decrypt_file, async_decrypt_reader, async_decrypt_stream, async_encryp_file,
+    async_encryp_stream, async_encryp_writer, encryp_option_builder, encryp_stream,
+    try_encryp_file,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+const BUFFER_SIZE: usize = 64;
+
+#[tokio::test]
+async fn async_output_is_identical() {
+    for opt in [
+        option("neko", BUFFER_SIZE),
+        authenticated_option("neko", BUFFER_SIZE),
+    ] {
+        for size in [0, 1, 63, 64, 65, 1000] {
+            let data = plaintext(size);
+            for known_size in [true, false] {
+                let file_size = if known_size { Some(size as u64) } else { None };
+                let mut expected: Vec<u8> = Vec::new();
+                encryp_stream(&mut &data[..], &mut expected, &opt, file_size).unwrap();
+
+                let mut encrypted: Vec<u8> = Vec::new();
+                async_encryp_stream(&mut &data[..], &mut encrypted, &opt, file_size)
+                    .await
+                    .unwrap();
+                assert!(encrypted == expected, "size {}", size);
+
+                let mut decrypted: Vec<u8> = Vec::new();
+                async_decrypt_stream(&mut &encrypted[..], &mut decrypted, &opt)
+                    .await
+                    .unwrap();
+                assert_eq!(decrypted, data);
+            }
+        }
+    }
+}
+
+#[tokio::test]
+async fn async_writer_and_reader_round_trip() {
+    let opt = authenticated_option("neko", BUFFER_SIZE);
+    let data = plaintext(1000);
+
+    let (client, mut server) = tokio::io::duplex(1 << 16);
+    let mut writer = async_encryp_writer::new(client, &opt, None);
+    for piece in data.chunks(100) {
+        writer.write_all(piece).await.unwrap();
+    }
+    writer.shutdown().await.unwrap();
+    drop(writer);
+
+    let mut encrypted: Vec<u8> = Vec::new();
+    server.read_to_end(&mut encrypted).await.unwrap();
+    assert_eq!(encrypted, encrypt(&data, &opt, false));
+
+    let mut reader = async_decrypt_reader::new(std::io::Cursor::new(encrypted.clone()), &opt);
+    let mut decrypted: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut decrypted).await.unwrap();
+    assert_eq!(decrypted, data);
+
+    let mut corrupted = encrypted.clone();
+    let last = corrupted.len() - 1;
+    corrupted[last] ^= 1;
+    let mut reader = async_decrypt_reader::new(std::io::Cursor::new(corrupted), &opt);
+    let mut decrypted: Vec<u8> = Vec::new();
+    assert!(reader.read_to_end(&mut decrypted).await.is_err());
+
+    let mut decrypted: Vec<u8> = Vec::new();
+    let result = async_decrypt_stream(&mut &encrypted[0..100], &mut decrypted, &opt).await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn async_files_match_sync_files() {
+    let dir = std::env::temp_dir().join(format!("neko-async-files-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    let name = |file: &str| dir.join(file).to_str().unwrap().to_string();
+    let data = plaintext(1000);
+    std::fs::write(name("plain"), &data).unwrap();
+
+    let threaded = encryp_option_builder::new("neko")
+        .buffer_size(BUFFER_SIZE)
+        .threads(3)
+        .salts(&SALT_A, &SALT_B)
+        .allow_salt_reuse(true)
+        .build()
+        .unwrap();
+    for opt in [authenticated_option("neko", BUFFER_SIZE), threaded] {
+        try_encryp_file(&name("plain"), &name("sync.neko"), &opt).unwrap();
+        async_encryp_file(&name("plain"), &name("async.neko"), &opt)
+            .await
+            .unwrap();
+        assert!(
+            std::fs::read(name("async.neko")).unwrap() == std::fs::read(name("sync.neko")).unwrap()
+        );
+
+        async_decrypt_file(&name("async.neko"), &name("async.out"), &opt)
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(name("async.out")).unwrap(), data);
+
+        // refused like by the sync functions, without creating the destination
+        assert!(async_encryp_file(&name("plain"), &name("sync.neko"), &opt)
+            .await
+            .is_err());
+        assert!(
+            async_decrypt_file(&name("missing"), &name("other.out"), &opt)
+                .await
+                .is_err()
+        );
+        assert!(!dir.join("other.out").exists());
+
+        let mut corrupted = std::fs::read(name("sync.neko")).unwrap();
+        let last = corrupted.len() - 1;
+        corrupted[last] ^= 1;
+        std::fs::write(name("corrupted.neko"), &corrupted).unwrap();
+        assert!(
+            async_decrypt_file(&name("corrupted.neko"), &name("corrupted.out"), &opt)
+                .await
+                .is_err()
+        );
+
+        for file in [
+            "sync.neko",
+            "async.neko",
+            "async.out",
+            "corrupted.neko",
+            "corrupted.out",
+        ] {
+            let _ = std::fs::remove_file(name(file));
+        }
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,67 @@
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use common::*;
+use encryp::{audit_stream, cipher_algorithm, encryp_option_builder, kdf_algorithm};
+
+#[test]
+fn audit_reports_parameters() {
+    let encrypted = encrypt(&plaintext(100), &option("neko", 64), true);
+    let info = audit_stream(&mut encrypted.as_slice()).unwrap();
+
+    assert_eq!(info.salt_a, SALT_A);
+    assert_eq!(info.salt_b, SALT_B);
+    assert_eq!(info.cipher, cipher_algorithm::tent_chaos);
+    assert_eq!(info.kdf, None);
+    assert!(!info.empty_password);
+    assert!(info.standard_head);
+
+    let opt = encryp_option_builder::new("neko")
+        .cipher(cipher_algorithm::tent_chaos_v2)
+        .build()
+        .unwrap();
+    let encrypted = encrypt(&plaintext(100), &opt, true);
+    let info = audit_stream(&mut encrypted.as_slice()).unwrap();
+    assert_eq!(info.cipher, cipher_algorithm::tent_chaos_v2);
+}
+
+#[test]
+fn audit_detects_empty_password() {
+    let encrypted = encrypt(&plaintext(10), &option("", 64), true);
+    assert!(
+        audit_stream(&mut encrypted.as_slice())
+            .unwrap()
+            .empty_password
+    );
+
+    let kdf = kdf_algorithm::argon2id {
+        memory: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+    for password in ["", "neko"] {
+        let opt = encryp_option_builder::new(password)
+            .kdf(kdf)
+            .build()
+            .unwrap();
+        let encrypted = encrypt(&plaintext(10), &opt, false);
+        let info = audit_stream(&mut encrypted.as_slice()).unwrap();
+        assert_eq!(info.kdf, Some(kdf));
+        assert_eq!(info.empty_password, password.is_empty());
+    }
+}
+
+#[test]
+fn audit_detects_non_standard_head() {
+    let mut encrypted = encrypt(&plaintext(10), &option("neko", 64), true);
+    encrypted[9] = 7;
+    assert!(
+        !audit_stream(&mut encrypted.as_slice())
+            .unwrap()
+            .standard_head
+    );
+
+    encrypted[0] = 7;
+    assert!(audit_stream(&mut encrypted.as_slice()).is_err());
+}
@@ -0,0 +1,131 @@
+//! The file format is little-endian on every host.
+//!
+//! The expected bytes are built by a reference model of the legacy format
+//! that only uses u64 arithmetic and explicit `to_le_bytes`/`from_le_bytes`,
+//! so they are the same on a big-endian host. On a little-endian host, where
+//! native and little-endian order agree, the tests with byte-swapped input
+//! show what a host of the other byte order would see.
+
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use common::*;
+use encryp::{keystream_cipher, tent_chaos};
+
+const BUFFER_SIZE: usize = 64;
+
+/// Initial value of the keystream: the XOR of the little-endian words of
+/// SHA3-512(password, salt B).
+fn reference_initial_value(password: &str) -> u64 {
+    let mut key: Vec<u8> = password.as_bytes().to_vec();
+    key.extend_from_slice(&SALT_B);
+    let hash = sha3_512(&key);
+
+    let mut value: u64 = 0;
+    for word in hash.chunks(8) {
+        value ^= u64::from_le_bytes(word.try_into().unwrap());
+    }
+    return value;
+}
+
+/// The legacy container of `data`, encrypted in pieces of `BUFFER_SIZE`.
+fn reference_container(data: &[u8], password: &str) -> Vec<u8> {
+    let mut tent = tent_chaos::new(reference_initial_value(password));
+    let mut ciphertext: Vec<u8> = Vec::new();
+    for piece in data.chunks(BUFFER_SIZE) {
+        for chunk in piece.chunks(8) {
+            // the rest of the last word of a piece is dropped
+            let word = tent.iterate().to_le_bytes();
+            ciphertext.extend(chunk.iter().zip(word).map(|(byte, key)| byte ^ key));
+        }
+    }
+
+    let mut password_hash: Vec<u8> = password.as_bytes().to_vec();
+    password_hash.extend_from_slice(&SALT_A);
+
+    let blocks = [
+        (SALT_A_BLOCK, SALT_A.to_vec()),
+        (114514, SALT_B.to_vec()),
+        (HASH_PASSWORD_BLOCK, sha3_512(&password_hash)),
+        (CIPHERTEXT_BLOCK, ciphertext),
+        (SHA3_512_BLOCK, sha3_512(data)),
+    ];
+
+    let mut ret: Vec<u8> = vec![0, 0, b'T', b'e', b'n', b't', 4, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    for (blk_type, content) in blocks {
+        ret.extend_from_slice(&blk_type.to_le_bytes());
+        ret.extend_from_slice(&(content.len() as u64).to_le_bytes());
+        ret.extend_from_slice(&content);
+    }
+    return ret;
+}
+
+#[test]
+fn container_matches_little_endian_model() {
+    let opt = option("neko", BUFFER_SIZE);
+    for size in [0, 1, 7, 8, 9, 63, 64, 65, 200] {
+        let data = plaintext(size);
+        let expected = reference_container(&data, "neko");
+
+        assert_eq!(encrypt(&data, &opt, true), expected, "size {}", size);
+        assert_eq!(decrypt(&expected, &opt).unwrap(), data, "size {}", size);
+    }
+}
+
+#[test]
+fn keystream_words_are_little_endian() {
+    let mut key = [0_u8; 64];
+    key[0] = 0x01;
+    key[15] = 0x80;
+    let value: u64 = 0x01 ^ 0x8000_0000_0000_0000;
+
+    let mut tent = tent_chaos::new(value);
+    let mut expected: Vec<u8> = Vec::new();
+    for _ in 0..4 {
+        expected.extend_from_slice(&tent.iterate().to_le_bytes());
+    }
+
+    let mut keystream = vec![0_u8; 32];
+    tent_chaos::from_key(&key).apply_keystream(&mut keystream);
+    assert_eq!(keystream, expected);
+
+    // a big-endian reading of the same words is a different keystream
+    let swapped: Vec<u8> = expected
+        .chunks(8)
+        .flat_map(|word| u64::from_le_bytes(word.try_into().unwrap()).to_be_bytes())
+        .collect();
+    assert_ne!(keystream, swapped);
+}
+
+#[test]
+fn byte_swapped_integers_are_rejected() {
+    let opt = option("neko", BUFFER_SIZE);
+    // whole words only, so the keystream can be recovered from the ciphertext
+    let data = plaintext(128);
+    let (head, blocks) = split_blocks(&encrypt(&data, &opt, true));
+
+    // what a writer using big-endian block heads would produce
+    let mut swapped: Vec<u8> = head.clone();
+    for (blk_type, content) in &blocks {
+        swapped.extend_from_slice(&blk_type.to_be_bytes());
+        swapped.extend_from_slice(&(content.len() as u64).to_be_bytes());
+        swapped.extend_from_slice(content);
+    }
+    assert!(decrypt(&swapped, &opt).is_err());
+
+    // and one applying the keystream words in big-endian order
+    let mut swapped_words = blocks.clone();
+    for (blk_type, content) in swapped_words.iter_mut() {
+        if *blk_type == CIPHERTEXT_BLOCK {
+            for (chunk, plain) in content.chunks_mut(8).zip(data.chunks(8)) {
+                let mut word: Vec<u8> = chunk.iter().zip(plain).map(|(c, p)| c ^ p).collect();
+                word.reverse();
+                for ((byte, key), p) in chunk.iter_mut().zip(word).zip(plain) {
+                    *byte = p ^ key;
+                }
+            }
+        }
+    }
+    assert!(decrypt(&join_blocks(&head, &swapped_words), &opt).is_err());
+}
@@ -0,0 +1,69 @@
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use common::*;
+use encryp::{
+    cancellation_token, encryp_error, encryp_option, encryp_option_builder, progress_hook,
+    try_decrypt_file, try_encryp_file,
+};
+
+/// Options that cancel themselves once `limit` bytes have been read.
+fn cancelling_option(limit: u64, threads: usize) -> encryp_option {
+    let cancel = cancellation_token::new();
+    let token = cancel.clone();
+    return encryp_option_builder::new("neko")
+        .keep(true)
+        .buffer_size(64)
+        .threads(threads)
+        .cancel(cancel)
+        .progress(progress_hook::new(move |info| {
+            if info.processed >= limit {
+                token.cancel();
+            }
+        }))
+        .build()
+        .unwrap();
+}
+
+#[test]
+fn cancelled_operations_remove_the_destination() {
+    let dir = std::env::temp_dir().join(format!("neko-cancel-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let name = |file: &str| dir.join(file).to_str().unwrap().to_string();
+
+    let data = plaintext(10000);
+    std::fs::write(name("plain"), &data).unwrap();
+
+    for threads in [1, 2] {
+        let result = try_encryp_file(
+            &name("plain"),
+            &name("cancelled.neko"),
+            &cancelling_option(1000, threads),
+        );
+        assert_eq!(result, Err(encryp_error::cancelled), "threads {}", threads);
+        assert!(!dir.join("cancelled.neko").exists());
+    }
+
+    let opt = encryp_option_builder::new("neko")
+        .keep(true)
+        .buffer_size(64)
+        .build()
+        .unwrap();
+    try_encryp_file(&name("plain"), &name("plain.neko"), &opt).unwrap();
+
+    let result = try_decrypt_file(
+        &name("plain.neko"),
+        &name("decrypted"),
+        &cancelling_option(1000, 1),
+    );
+    assert_eq!(result, Err(encryp_error::cancelled));
+    assert!(!dir.join("decrypted").exists());
+
+    // the sources are left alone
+    assert_eq!(std::fs::read(name("plain")).unwrap(), data);
+    try_decrypt_file(&name("plain.neko"), &name("decrypted"), &opt).unwrap();
+    assert_eq!(std::fs::read(name("decrypted")).unwrap(), data);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,74 @@
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use common::*;
+
+/// Runs neko with `stdin` piped in, returns whether it succeeded and what it
+/// wrote on stdout.
+fn neko(args: &[&str], stdin: &[u8]) -> (bool, Vec<u8>) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_neko"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+
+    let mut input = child.stdin.take().unwrap();
+    let stdin = stdin.to_vec();
+    // neko may stop reading early, e.g. after a wrong password
+    let writer = std::thread::spawn(move || {
+        let _ = input.write_all(&stdin);
+    });
+
+    let output = child.wait_with_output().unwrap();
+    writer.join().unwrap();
+    return (output.status.success(), output.stdout);
+}
+
+#[test]
+fn failed_pipe_decryption_removes_the_output() {
+    let dir = std::env::temp_dir().join(format!("neko-cli-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    let out = dir.join("t.out");
+    let out_name = out.to_str().unwrap();
+
+    let data = plaintext(100000);
+    let encrypted = encrypt(&data, &authenticated_option("neko", 4096), true);
+
+    assert!(neko(&["-d", "-p", "neko", "-", "-o", out_name], &encrypted).0);
+    assert_eq!(fs::read(&out).unwrap(), data);
+    fs::remove_file(&out).unwrap();
+
+    // the MAC fails only after the whole plaintext has been written
+    let mut corrupted = encrypted.clone();
+    let last = corrupted.len() - 200;
+    corrupted[last] ^= 1;
+    assert!(!neko(&["-d", "-p", "neko", "-", "-o", out_name], &corrupted).0);
+    assert!(!Path::new(&out).exists());
+
+    assert!(!neko(&["-d", "-p", "nya", "-", "-o", out_name], &encrypted).0);
+    assert!(!Path::new(&out).exists());
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn piped_plaintext_is_all_that_goes_to_stdout() {
+    let data = plaintext(1000);
+    let opt = option("neko", 4096);
+    let (head, mut blocks) = split_blocks(&encrypt(&data, &opt, true));
+    blocks.insert(1, (7777, vec![0x42; 20]));
+    let encrypted = join_blocks(&head, &blocks);
+
+    let (success, stdout) = neko(&["-d", "-p", "neko", "-"], &encrypted);
+    assert!(success);
+    assert_eq!(stdout, data);
+}
@@ -0,0 +1,126 @@
+//! Helpers shared by the integration tests.
+
+#![allow(dead_code)]
+
+use encryp::{decrypt_stream, encryp_option, encryp_option_builder, encryp_stream, kdf_algorithm};
+
+pub const SALT_A: [u8; 16] = [0x5a; 16];
+pub const SALT_B: [u8; 16] = [0xa5; 16];
+
+/// Block types of the file format, see `data_block_type`.
+pub const SALT_A_BLOCK: u64 = 42;
+pub const HASH_PASSWORD_BLOCK: u64 = 1919810;
+pub const CIPHERTEXT_BLOCK: u64 = 666;
+pub const SHA3_512_BLOCK: u64 = 2300;
+pub const SEGMENT_HASHES_BLOCK: u64 = 3002;
+pub const MAC_BLOCK: u64 = 3102;
+pub const HEADER_TAG_BLOCK: u64 = 3103;
+pub const CIPHER_BLOCK: u64 = 3104;
+
+pub fn sha3_512(data: &[u8]) -> Vec<u8> {
+    use sha3::Digest;
+    return sha3::Sha3_512::digest(data).to_vec();
+}
+
+pub fn plaintext(size: usize) -> Vec<u8> {
+    return (0..size).map(|i| ((i * 131 + 7) % 256) as u8).collect();
+}
+
+/// Options of the legacy format with fixed salts, so tests are reproducible.
+pub fn option(password: &str, buffer_size: usize) -> encryp_option {
+    return encryp_option_builder::new(password)
+        .buffer_size(buffer_size)
+        .salts(&SALT_A, &SALT_B)
+        .allow_salt_reuse(true)
+        .authenticated(false)
+        .build()
+        .unwrap();
+}
+
+/// Like `option`, in the authenticated format of new files.
+pub fn authenticated_option(password: &str, buffer_size: usize) -> encryp_option {
+    return encryp_option_builder::new(password)
+        .buffer_size(buffer_size)
+        .salts(&SALT_A, &SALT_B)
+        .allow_salt_reuse(true)
+        .build()
+        .unwrap();
+}
+
+/// Like `authenticated_option`, with a cheap argon2id.
+pub fn keyed_option(password: &str, buffer_size: usize) -> encryp_option {
+    return encryp_option_builder::new(password)
+        .buffer_size(buffer_size)
+        .salts(&SALT_A, &SALT_B)
+        .allow_salt_reuse(true)
+        .kdf(kdf_algorithm::argon2id {
+            memory: 64,
+            iterations: 1,
+            parallelism: 1,
+        })
+        .build()
+        .unwrap();
+}
+
+/// Encrypts `data`, as a file of known size or as a stream if `known_size`
+/// is false.
+pub fn encrypt(data: &[u8], opt: &encryp_option, known_size: bool) -> Vec<u8> {
+    let file_size = if known_size {
+        Some(data.len() as u64)
+    } else {
+        None
+    };
+
+    let mut encrypted: Vec<u8> = Vec::new();
+    encryp_stream(&mut &data[..], &mut encrypted, opt, file_size).unwrap();
+    return encrypted;
+}
+
+pub fn decrypt(encrypted: &[u8], opt: &encryp_option) -> Result<Vec<u8>, String> {
+    let mut decrypted: Vec<u8> = Vec::new();
+    decrypt_stream(&mut &encrypted[..], &mut decrypted, opt)?;
+    return Ok(decrypted);
+}
+
+/// The file head and the data blocks of a container whose ciphertext has a
+/// known length, as `(type, content)`.
+pub fn split_blocks(encrypted: &[u8]) -> (Vec<u8>, Vec<(u64, Vec<u8>)>) {
+    let head = encrypted[0..16].to_vec();
+    let mut blocks: Vec<(u64, Vec<u8>)> = Vec::new();
+
+    let mut pos: usize = 16;
+    while pos < encrypted.len() {
+        let blk_type = u64::from_le_bytes(encrypted[pos..(pos + 8)].try_into().unwrap());
+        let blk_len = u64::from_le_bytes(encrypted[(pos + 8)..(pos + 16)].try_into().unwrap());
+        let begin = pos + 16;
+        let end = begin + blk_len as usize;
+        blocks.push((blk_type, encrypted[begin..end].to_vec()));
+        pos = end;
+    }
+
+    return (head, blocks);
+}
+
+pub fn join_blocks(head: &[u8], blocks: &[(u64, Vec<u8>)]) -> Vec<u8> {
+    let mut ret: Vec<u8> = head.to_vec();
+    for (blk_type, content) in blocks {
+        ret.extend_from_slice(&blk_type.to_le_bytes());
+        ret.extend_from_slice(&(content.len() as u64).to_le_bytes());
+        ret.extend_from_slice(content);
+    }
+    return ret;
+}
+
+/// Offset of the content of the first block of type `blk_type`, which must
+/// be in front of the ciphertext or be the ciphertext itself.
+pub fn block_offset(encrypted: &[u8], blk_type: u64) -> usize {
+    let mut pos: usize = 16;
+    loop {
+        let t = u64::from_le_bytes(encrypted[pos..(pos + 8)].try_into().unwrap());
+        let blk_len = u64::from_le_bytes(encrypted[(pos + 8)..(pos + 16)].try_into().unwrap());
+        if t == blk_type {
+            return pos + 16;
+        }
+        pos += 16 + blk_len as usize;
+    }
+}
@@ -0,0 +1,107 @@
+//! Known-answer tests for the legacy format: `tent_chaos`, the legacy KDF,
+//! no compression, padding or segments.
+//!
+//! `vectors/legacy_<size>.neko` encrypts `<size>` bytes of `plaintext` with
+//! the password "neko", salt A 0..16, salt B 16..32 and a 64 byte buffer.
+//! `legacy_100_empty_password.neko` does so with an empty password. They
+//! were checked to decrypt with the original implementation; a change to
+//! any of them breaks every file already written.
+
+#![allow(clippy::needless_return)]
+
+use encryp::{decrypt_stream, encryp_option, encryp_option_builder, encryp_stream, tent_chaos};
+
+const VECTORS: [(&str, usize, &str); 10] = [
+    ("legacy_0.neko", 0, "neko"),
+    ("legacy_1.neko", 1, "neko"),
+    ("legacy_7.neko", 7, "neko"),
+    ("legacy_8.neko", 8, "neko"),
+    ("legacy_9.neko", 9, "neko"),
+    ("legacy_63.neko", 63, "neko"),
+    ("legacy_64.neko", 64, "neko"),
+    ("legacy_65.neko", 65, "neko"),
+    ("legacy_1000.neko", 1000, "neko"),
+    ("legacy_100_empty_password.neko", 100, ""),
+];
+
+fn plaintext(size: usize) -> Vec<u8> {
+    return (0..size).map(|i| ((i * 131 + 7) % 256) as u8).collect();
+}
+
+fn vector_option(password: &str) -> encryp_option {
+    let salt_a: Vec<u8> = (0..16).collect();
+    let salt_b: Vec<u8> = (16..32).collect();
+    return encryp_option_builder::new(password)
+        .buffer_size(64)
+        .salts(&salt_a, &salt_b)
+        .allow_salt_reuse(true)
+        .authenticated(false)
+        .build()
+        .unwrap();
+}
+
+fn read_vector(name: &str) -> Vec<u8> {
+    let path = format!("{}/tests/vectors/{}", env!("CARGO_MANIFEST_DIR"), name);
+    return std::fs::read(&path).unwrap_or_else(|e| panic!("{} : {}", path, e));
+}
+
+#[test]
+fn tent_chaos_sequence() {
+    let expected: [u64; 8] = [
+        0x048d159e26af37bc,
+        0x123456789abcdf00,
+        0x48d159e26af37c20,
+        0x23456789abcdf0b0,
+        0x8d159e26af37c300,
+        0xe5d4c3b2a19079d6,
+        0x3456789abcdf0c22,
+        0xd159e26af37c30f8,
+    ];
+
+    let mut tent = tent_chaos::new(0x0123456789abcdef);
+    for value in expected {
+        assert_eq!(tent.iterate(), value);
+    }
+}
+
+#[test]
+fn encryption_matches_vectors() {
+    for (name, size, password) in VECTORS {
+        let mut encrypted: Vec<u8> = Vec::new();
+        encryp_stream(
+            &mut plaintext(size).as_slice(),
+            &mut encrypted,
+            &vector_option(password),
+            Some(size as u64),
+        )
+        .unwrap();
+
+        assert!(encrypted == read_vector(name), "{} differs", name);
+    }
+}
+
+#[test]
+fn decryption_of_vectors() {
+    for (name, size, password) in VECTORS {
+        let mut decrypted: Vec<u8> = Vec::new();
+        decrypt_stream(
+            &mut read_vector(name).as_slice(),
+            &mut decrypted,
+            &encryp_option::create(false, false, password, 64),
+        )
+        .unwrap();
+
+        assert!(decrypted == plaintext(size), "{} differs", name);
+    }
+}
+
+#[test]
+fn decryption_of_vectors_rejects_wrong_password() {
+    let mut decrypted: Vec<u8> = Vec::new();
+    let result = decrypt_stream(
+        &mut read_vector("legacy_64.neko").as_slice(),
+        &mut decrypted,
+        &encryp_option::create(false, false, "nya", 64),
+    );
+    assert!(result.is_err());
+}
@@ -0,0 +1,296 @@
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::io::{Cursor, Read};
+
+use common::*;
+use encryp::{
+    decrypt_parallel, decrypt_reader, decrypt_stream, encryp_option, encryp_option_builder,
+    encryp_parallel, pack_stream, unpack_archive,
+};
+
+const BUFFER_SIZE: usize = 64;
+
+fn block_types(encrypted: &[u8]) -> Vec<u64> {
+    return split_blocks(encrypted).1.iter().map(|(t, _)| *t).collect();
+}
+
+/// Authenticated options with the legacy kdf and with argon2id.
+fn authenticated_options() -> [encryp_option; 2] {
+    return [
+        authenticated_option("neko", BUFFER_SIZE),
+        keyed_option("neko", BUFFER_SIZE),
+    ];
+}
+
+#[test]
+fn keyed_file_has_no_plaintext_digest() {
+    let data = plaintext(1000);
+
+    for opt in authenticated_options() {
+        let encrypted = encrypt(&data, &opt, true);
+        let types = block_types(&encrypted);
+        assert!(!types.contains(&SHA3_512_BLOCK));
+        assert_eq!(types.last(), Some(&MAC_BLOCK));
+    }
+
+    // the builder authenticates new files by default
+    let opt = encryp_option_builder::new("neko").build().unwrap();
+    let types = block_types(&encrypt(&data, &opt, true));
+    assert!(!types.contains(&SHA3_512_BLOCK));
+    assert_eq!(types.last(), Some(&MAC_BLOCK));
+
+    // `create` keeps the format older releases read
+    let opt = encryp_option::create(false, false, "neko", BUFFER_SIZE);
+    let types = block_types(&encrypt(&data, &opt, true));
+    assert!(types.contains(&SHA3_512_BLOCK));
+    assert!(!types.contains(&MAC_BLOCK));
+
+    let legacy = encrypt(&data, &option("neko", BUFFER_SIZE), true);
+    let types = block_types(&legacy);
+    assert!(types.contains(&SHA3_512_BLOCK));
+    assert!(!types.contains(&MAC_BLOCK));
+}
+
+#[test]
+fn flipped_bit_in_keyed_file_is_rejected() {
+    for opt in authenticated_options() {
+        for known_size in [true, false] {
+            let encrypted = encrypt(&plaintext(40), &opt, known_size);
+            assert_eq!(decrypt(&encrypted, &opt).unwrap(), plaintext(40));
+
+            for byte in (0..5).chain(16..encrypted.len()) {
+                let mut corrupted = encrypted.clone();
+                corrupted[byte] ^= 1 << (byte % 8);
+                assert!(decrypt(&corrupted, &opt).is_err(), "byte {}", byte);
+            }
+        }
+    }
+}
+
+#[test]
+fn missing_or_moved_mac_is_rejected() {
+    let data = plaintext(100);
+    for opt in authenticated_options() {
+        let (head, blocks) = split_blocks(&encrypt(&data, &opt, true));
+
+        let mut without_mac = blocks.clone();
+        without_mac.pop();
+        assert!(decrypt(&join_blocks(&head, &without_mac), &opt).is_err());
+
+        // an unkeyed digest does not stand in for the MAC
+        let mut downgraded = without_mac.clone();
+        downgraded.push((SHA3_512_BLOCK, sha3_512(&data)));
+        assert!(decrypt(&join_blocks(&head, &downgraded), &opt).is_err());
+
+        let mut trailing = blocks.clone();
+        trailing.push((9999, vec![0; 8]));
+        assert!(decrypt(&join_blocks(&head, &trailing), &opt).is_err());
+    }
+}
+
+#[test]
+fn segment_hashes_are_keyed() {
+    let data = plaintext(1000);
+    let builder = encryp_option_builder::new("neko")
+        .buffer_size(BUFFER_SIZE)
+        .segment_size(128)
+        .threads(3)
+        .salts(&SALT_A, &SALT_B)
+        .allow_salt_reuse(true);
+
+    let legacy = builder.clone().authenticated(false).build().unwrap();
+    let authenticated = builder.build().unwrap();
+    let keyed = builder
+        .clone()
+        .kdf(keyed_option("neko", BUFFER_SIZE).kdf)
+        .build()
+        .unwrap();
+
+    let mut hashes: Vec<Vec<u8>> = Vec::new();
+    for opt in [&legacy, &authenticated, &keyed] {
+        let mut encrypted: Vec<u8> = Vec::new();
+        encryp_parallel(&mut &data[..], &mut encrypted, opt, 1000).unwrap();
+
+        let mut decrypted: Vec<u8> = Vec::new();
+        decrypt_parallel(&mut Cursor::new(&encrypted), &mut decrypted, opt).unwrap();
+        assert_eq!(decrypted, data);
+
+        let mut reader = decrypt_reader::new(Cursor::new(&encrypted), opt).unwrap();
+        let mut decrypted: Vec<u8> = Vec::new();
+        reader.read_to_end(&mut decrypted).unwrap();
+        assert_eq!(decrypted, data);
+
+        let (_, blocks) = split_blocks(&encrypted);
+        let (_, segment_hashes) = blocks
+            .iter()
+            .find(|(t, _)| *t == SEGMENT_HASHES_BLOCK)
+            .unwrap();
+        hashes.push(segment_hashes.clone());
+    }
+
+    assert_eq!(&hashes[0][0..64], sha3_512(&data[0..128]).as_slice());
+    assert_ne!(hashes[0], hashes[1]);
+    assert_ne!(hashes[0], hashes[2]);
+}
+
+#[test]
+fn segmented_file_cut_at_a_segment_boundary_is_rejected() {
+    for opt in authenticated_options() {
+        let opt = encryp_option_builder::new("neko")
+            .buffer_size(BUFFER_SIZE)
+            .segment_size(128)
+            .salts(&SALT_A, &SALT_B)
+            .allow_salt_reuse(true)
+            .kdf(opt.kdf)
+            .build()
+            .unwrap();
+        let encrypted = encrypt(&plaintext(1000), &opt, true);
+        assert!(decrypt_reader::new(Cursor::new(&encrypted), &opt).is_ok());
+
+        let (head, mut blocks) = split_blocks(&encrypted);
+        for (blk_type, data) in blocks.iter_mut() {
+            if *blk_type == CIPHERTEXT_BLOCK {
+                data.truncate(896);
+            } else if *blk_type == SEGMENT_HASHES_BLOCK {
+                data.truncate(448);
+            }
+        }
+
+        let with_mac = join_blocks(&head, &blocks);
+        blocks.pop();
+        let without_mac = join_blocks(&head, &blocks);
+
+        for truncated in [with_mac, without_mac] {
+            assert!(decrypt_reader::new(Cursor::new(&truncated), &opt).is_err());
+            assert!(decrypt(&truncated, &opt).is_err());
+        }
+    }
+}
+
+#[test]
+fn header_blocks_are_bound_to_the_file() {
+    let data = plaintext(100);
+    for opt in authenticated_options() {
+        let other = encryp_option_builder::new("neko")
+            .buffer_size(BUFFER_SIZE)
+            .salts(&[0x11; 16], &SALT_B)
+            .allow_salt_reuse(true)
+            .kdf(opt.kdf)
+            .build()
+            .unwrap();
+
+        let (head, blocks) = split_blocks(&encrypt(&data, &opt, true));
+        let (_, other_blocks) = split_blocks(&encrypt(&data, &other, true));
+
+        // salt A and the password hash of the other file are valid for the password
+        let mut swapped = blocks.clone();
+        for (blk_type, content) in swapped.iter_mut() {
+            if *blk_type == SALT_A_BLOCK || *blk_type == HASH_PASSWORD_BLOCK {
+                *content = other_blocks
+                    .iter()
+                    .find(|(t, _)| t == blk_type)
+                    .unwrap()
+                    .1
+                    .clone();
+            }
+        }
+        let err = decrypt(&join_blocks(&head, &swapped), &opt).unwrap_err();
+        assert!(err.contains("Header tag"), "{}", err);
+    }
+}
+
+#[test]
+fn header_tag_can_not_be_dropped_or_added() {
+    let data = plaintext(100);
+    let opt = authenticated_option("neko", BUFFER_SIZE);
+    let (head, blocks) = split_blocks(&encrypt(&data, &opt, true));
+
+    // read as a legacy file, the password hash no longer matches
+    for drop_mac in [false, true] {
+        let mut dropped: Vec<(u64, Vec<u8>)> = blocks
+            .iter()
+            .filter(|(t, _)| *t != HEADER_TAG_BLOCK)
+            .cloned()
+            .collect();
+        if drop_mac {
+            dropped.pop();
+            dropped.push((SHA3_512_BLOCK, sha3_512(&data)));
+        }
+
+        let mut decrypted: Vec<u8> = Vec::new();
+        let result = decrypt_stream(&mut &join_blocks(&head, &dropped)[..], &mut decrypted, &opt);
+        assert!(result.is_err());
+        assert!(decrypted.is_empty());
+    }
+
+    let legacy = option("neko", BUFFER_SIZE);
+    let (head, mut blocks) = split_blocks(&encrypt(&data, &legacy, true));
+    let ciphertext = blocks.len() - 2;
+    blocks.insert(ciphertext, (HEADER_TAG_BLOCK, vec![0; 64]));
+    assert!(decrypt(&join_blocks(&head, &blocks), &legacy).is_err());
+}
+
+#[test]
+fn unknown_header_block_is_rejected_before_decryption() {
+    for opt in authenticated_options() {
+        let encrypted = encrypt(&plaintext(100), &opt, true);
+        let (head, blocks) = split_blocks(&encrypted);
+
+        for position in 0..=blocks.len() {
+            let mut inserted = blocks.clone();
+            inserted.insert(position, (9999, vec![0; 8]));
+
+            let mut decrypted: Vec<u8> = Vec::new();
+            let result = decrypt_stream(
+                &mut &join_blocks(&head, &inserted)[..],
+                &mut decrypted,
+                &opt,
+            );
+            assert!(result.is_err(), "position {}", position);
+            if position < blocks.len() - 1 {
+                assert!(decrypted.is_empty(), "position {}", position);
+            }
+        }
+    }
+}
+
+#[test]
+fn archive_payload_is_covered_by_the_mac() {
+    let dir = std::env::temp_dir().join(format!("neko-mac-archive-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("in")).unwrap();
+    let input = dir.join("in").join("file");
+    std::fs::write(&input, plaintext(100)).unwrap();
+    let name = dir.join("packed.neko").to_str().unwrap().to_string();
+    let out = dir.join("out").to_str().unwrap().to_string();
+
+    let opt = encryp_option_builder::new("neko")
+        .buffer_size(BUFFER_SIZE)
+        .padding(encryp::padding_policy::bucket(4096))
+        .build()
+        .unwrap();
+    let mut packed: Vec<u8> = Vec::new();
+    pack_stream(&[input.to_str().unwrap().to_string()], &mut packed, &opt).unwrap();
+    assert_eq!(block_types(&packed).last(), Some(&MAC_BLOCK));
+
+    std::fs::write(&name, &packed).unwrap();
+    unpack_archive(&name, &out, &[], &opt).unwrap();
+    assert_eq!(
+        std::fs::read(dir.join("out").join("file")).unwrap(),
+        plaintext(100)
+    );
+
+    // the padding is not covered by the hash of any member
+    let mac_offset = packed.len() - 80;
+    let mut corrupted = packed.clone();
+    corrupted[mac_offset - 1] ^= 1;
+    std::fs::write(&name, &corrupted).unwrap();
+    let err = unpack_archive(&name, &out, &[], &opt).unwrap_err();
+    assert!(err.contains("MAC"), "{}", err);
+
+    std::fs::write(&name, &packed[0..mac_offset]).unwrap();
+    assert!(unpack_archive(&name, &out, &[], &opt).is_err());
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,74 @@
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::sync::{Arc, Mutex};
+
+use common::*;
+use encryp::{
+    encryp_option, encryp_option_builder, progress_hook, try_decrypt_file, try_encryp_file,
+};
+
+#[allow(non_camel_case_types)]
+type report = (Option<String>, u64, Option<u64>);
+
+/// Options recording every report of their progress hook.
+fn recording_option(threads: usize) -> (encryp_option, Arc<Mutex<Vec<report>>>) {
+    let reports: Arc<Mutex<Vec<report>>> = Arc::new(Mutex::new(Vec::new()));
+    let recorded = reports.clone();
+    let opt = encryp_option_builder::new("neko")
+        .keep(true)
+        .cover_existing_file(true)
+        .buffer_size(64)
+        .threads(threads)
+        .progress(progress_hook::new(move |info| {
+            recorded.lock().unwrap().push((
+                info.file.map(String::from),
+                info.processed,
+                info.total,
+            ));
+        }))
+        .build()
+        .unwrap();
+    return (opt, reports);
+}
+
+fn check_reports(reports: &[report], file: &str, total: u64) {
+    assert!(!reports.is_empty());
+    for (idx, (name, processed, reported_total)) in reports.iter().enumerate() {
+        assert_eq!(name.as_deref(), Some(file));
+        assert_eq!(*reported_total, Some(total));
+        if idx > 0 {
+            assert!(*processed >= reports[idx - 1].1, "{:?}", reports);
+        }
+    }
+    assert_eq!(reports.last().unwrap().1, total, "{}", file);
+}
+
+#[test]
+fn progress_reaches_the_total() {
+    let dir = std::env::temp_dir().join(format!("neko-progress-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let name = |file: &str| dir.join(file).to_str().unwrap().to_string();
+
+    let data = plaintext(10000);
+    std::fs::write(name("plain"), &data).unwrap();
+
+    for threads in [1, 3] {
+        let (opt, reports) = recording_option(threads);
+        try_encryp_file(&name("plain"), &name("plain.neko"), &opt).unwrap();
+        check_reports(&reports.lock().unwrap(), &name("plain"), data.len() as u64);
+
+        let (opt, reports) = recording_option(threads);
+        try_decrypt_file(&name("plain.neko"), &name("decrypted"), &opt).unwrap();
+        assert_eq!(std::fs::read(name("decrypted")).unwrap(), data);
+        // the total of a decryption is the length of the ciphertext
+        check_reports(
+            &reports.lock().unwrap(),
+            &name("plain.neko"),
+            data.len() as u64,
+        );
+    }
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
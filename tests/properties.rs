@@ -0,0 +1,69 @@
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use common::*;
+use proptest::prelude::*;
+
+fn buffer_size() -> impl Strategy<Value = usize> {
+    return (1_usize..=64).prop_map(|blocks| blocks * 8);
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(128))]
+
+    #[test]
+    fn round_trip(
+        data in proptest::collection::vec(any::<u8>(), 0..2048),
+        password in ".{0,16}",
+        encryp_buffer in buffer_size(),
+        decrypt_buffer in buffer_size(),
+        known_size in any::<bool>(),
+    ) {
+        let encrypted = encrypt(&data, &option(&password, encryp_buffer), known_size);
+        let decrypted = decrypt(&encrypted, &option(&password, decrypt_buffer));
+        prop_assert_eq!(decrypted, Ok(data));
+    }
+
+    #[test]
+    fn other_password_is_rejected(
+        data in proptest::collection::vec(any::<u8>(), 0..256),
+        password in ".{0,16}",
+        other in ".{0,16}",
+    ) {
+        prop_assume!(password != other);
+        let encrypted = encrypt(&data, &option(&password, 64), true);
+        prop_assert!(decrypt(&encrypted, &option(&other, 64)).is_err());
+    }
+
+    #[test]
+    fn flipped_ciphertext_bit_is_rejected(
+        data in proptest::collection::vec(any::<u8>(), 1..512),
+        position in any::<prop::sample::Index>(),
+        bit in 0_u8..8,
+        known_size in any::<bool>(),
+    ) {
+        let opt = option("neko", 64);
+        let mut encrypted = encrypt(&data, &opt, known_size);
+
+        // the ciphertext, in one piece or in chunks, up to the checksum block
+        let begin = block_offset(&encrypted, HASH_PASSWORD_BLOCK) + 64 + 16;
+        let end = encrypted.len() - 16 - 64;
+        let byte = begin + position.index(end - begin);
+        encrypted[byte] ^= 1 << bit;
+
+        prop_assert!(decrypt(&encrypted, &opt).is_err());
+    }
+
+    #[test]
+    fn truncation_is_rejected(
+        data in proptest::collection::vec(any::<u8>(), 0..512),
+        len in any::<prop::sample::Index>(),
+        known_size in any::<bool>(),
+    ) {
+        let opt = option("neko", 64);
+        let encrypted = encrypt(&data, &opt, known_size);
+        let len = len.index(encrypted.len());
+        prop_assert!(decrypt(&encrypted[0..len], &opt).is_err());
+    }
+}
@@ -0,0 +1,446 @@
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use common::*;
+use encryp::{
+    cipher_algorithm, compression_algorithm, compression_option, decrypt_parallel, decrypt_reader,
+    encryp_option, encryp_option_builder, encryp_parallel, encryp_stream, inspect_stream,
+    kdf_algorithm, padding_policy,
+};
+
+const BUFFER_SIZE: usize = 64;
+
+/// Sizes around the 8 byte blocks of the cipher and around the buffer.
+fn boundary_sizes() -> Vec<usize> {
+    let mut sizes: Vec<usize> = vec![0, 1, 7, 8, 9, 15, 16, 17];
+    for multiple in [1, 2, 3] {
+        let edge = BUFFER_SIZE * multiple;
+        sizes.extend((edge - 9)..=(edge + 9));
+    }
+    return sizes;
+}
+
+#[test]
+fn round_trip_around_boundaries() {
+    let opt = option("neko", BUFFER_SIZE);
+    for size in boundary_sizes() {
+        for known_size in [true, false] {
+            let data = plaintext(size);
+            let encrypted = encrypt(&data, &opt, known_size);
+            assert_eq!(decrypt(&encrypted, &opt).unwrap(), data, "size {}", size);
+        }
+    }
+}
+
+#[test]
+fn round_trip_with_other_buffer_size() {
+    let data = plaintext(1000);
+    let encrypted = encrypt(&data, &option("neko", BUFFER_SIZE), true);
+    for buffer_size in [8, 24, 4096] {
+        assert_eq!(
+            decrypt(&encrypted, &option("neko", buffer_size)).unwrap(),
+            data
+        );
+    }
+}
+
+#[test]
+fn round_trip_with_unaligned_buffer_size() {
+    for buffer_size in [1, 12, 100] {
+        for size in [0, 7, 99, 100, 101, 1000] {
+            let data = plaintext(size);
+            for known_size in [true, false] {
+                let opt = encryp_option::create(true, true, "neko", buffer_size);
+                let encrypted = encrypt(&data, &opt, known_size);
+                assert_eq!(decrypt(&encrypted, &opt).unwrap(), data, "size {}", size);
+                assert_eq!(decrypt(&encrypted, &option("neko", 64)).unwrap(), data);
+            }
+        }
+    }
+}
+
+#[test]
+fn invalid_options_are_refused_by_every_operation() {
+    let data = plaintext(100);
+    let encrypted = encrypt(&data, &option("neko", BUFFER_SIZE), true);
+
+    let opt = encryp_option::create(true, true, "neko", 0);
+    let mut output: Vec<u8> = Vec::new();
+    assert!(encryp_stream(&mut &data[..], &mut output, &opt, Some(100)).is_err());
+    assert!(encryp_stream(&mut &data[..], &mut output, &opt, None).is_err());
+    assert!(encryp_parallel(&mut &data[..], &mut output, &opt, 100).is_err());
+    assert!(decrypt(&encrypted, &opt).is_err());
+    assert!(decrypt_parallel(&mut Cursor::new(&encrypted), &mut output, &opt).is_err());
+    assert!(decrypt_reader::new(Cursor::new(&encrypted), &opt).is_err());
+
+    let mut opt = encryp_option::create(true, true, "neko", BUFFER_SIZE);
+    opt.threads = 0;
+    assert!(encryp_stream(&mut &data[..], &mut output, &opt, None).is_err());
+}
+
+#[test]
+fn round_trip_empty_file() {
+    let opt = option("", BUFFER_SIZE);
+    let encrypted = encrypt(&[], &opt, true);
+    assert!(decrypt(&encrypted, &opt).unwrap().is_empty());
+}
+
+#[test]
+fn round_trip_with_options() {
+    let builders = [
+        encryp_option_builder::new("neko").compression(compression_option {
+            algorithm: compression_algorithm::zstd,
+            level: 3,
+            auto: false,
+        }),
+        encryp_option_builder::new("neko").compression(compression_option {
+            algorithm: compression_algorithm::lz4,
+            level: 0,
+            auto: false,
+        }),
+        encryp_option_builder::new("neko").padding(padding_policy::padme),
+        encryp_option_builder::new("neko").padding(padding_policy::bucket(100)),
+        encryp_option_builder::new("neko").segment_size(128),
+        encryp_option_builder::new("neko").kdf(kdf_algorithm::argon2id {
+            memory: 64,
+            iterations: 1,
+            parallelism: 1,
+        }),
+        encryp_option_builder::new("neko").cipher(cipher_algorithm::tent_chaos_v2),
+    ];
+
+    for builder in builders {
+        let builder = builder.buffer_size(BUFFER_SIZE);
+        for size in [0, 1, 63, 64, 65, 1000] {
+            let data = plaintext(size);
+            for known_size in [true, false] {
+                let opt = builder.build().unwrap();
+                let encrypted = encrypt(&data, &opt, known_size);
+                assert_eq!(decrypt(&encrypted, &opt).unwrap(), data, "{:?}", opt);
+            }
+        }
+    }
+}
+
+#[test]
+fn cipher_is_read_from_the_file() {
+    let data = plaintext(100);
+    let legacy = encrypt(&data, &option("neko", BUFFER_SIZE), true);
+    let (_, legacy_blocks) = split_blocks(&legacy);
+    assert!(legacy_blocks
+        .iter()
+        .all(|(blk_type, _)| *blk_type != CIPHER_BLOCK));
+
+    let opt = encryp_option_builder::new("neko")
+        .salts(&SALT_A, &SALT_B)
+        .allow_salt_reuse(true)
+        .buffer_size(BUFFER_SIZE)
+        .cipher(cipher_algorithm::tent_chaos_v2)
+        .build()
+        .unwrap();
+    let encrypted = encrypt(&data, &opt, true);
+    let (head, mut blocks) = split_blocks(&encrypted);
+    assert!(blocks.contains(&(CIPHER_BLOCK, 1_u64.to_le_bytes().to_vec())));
+    let ciphertext = |blocks: &[(u64, Vec<u8>)]| {
+        return blocks
+            .iter()
+            .find(|(blk_type, _)| *blk_type == CIPHERTEXT_BLOCK)
+            .unwrap()
+            .1
+            .clone();
+    };
+    assert_ne!(ciphertext(&blocks), ciphertext(&legacy_blocks));
+
+    // decrypting with the default cipher uses the one of the file
+    assert_eq!(
+        decrypt(&encrypted, &option("neko", BUFFER_SIZE)).unwrap(),
+        data
+    );
+
+    for (blk_type, content) in blocks.iter_mut() {
+        if *blk_type == CIPHER_BLOCK {
+            *content = 2_u64.to_le_bytes().to_vec();
+        }
+    }
+    assert!(decrypt(&join_blocks(&head, &blocks), &opt).is_err());
+}
+
+#[test]
+fn round_trip_parallel() {
+    let builder = encryp_option_builder::new("neko")
+        .buffer_size(BUFFER_SIZE)
+        .segment_size(128)
+        .threads(3);
+
+    for size in [0, 127, 128, 129, 1000] {
+        let opt = builder.build().unwrap();
+        let data = plaintext(size);
+
+        let mut encrypted: Vec<u8> = Vec::new();
+        encryp_parallel(&mut &data[..], &mut encrypted, &opt, size as u64).unwrap();
+
+        let mut decrypted: Vec<u8> = Vec::new();
+        decrypt_parallel(&mut Cursor::new(&encrypted), &mut decrypted, &opt).unwrap();
+        assert_eq!(decrypted, data);
+
+        assert_eq!(decrypt(&encrypted, &opt).unwrap(), data);
+    }
+}
+
+#[test]
+fn seek_in_segmented_file() {
+    let opt = encryp_option_builder::new("neko")
+        .buffer_size(BUFFER_SIZE)
+        .segment_size(128)
+        .build()
+        .unwrap();
+    let data = plaintext(1000);
+    let encrypted = encrypt(&data, &opt, true);
+
+    let mut reader = decrypt_reader::new(Cursor::new(encrypted), &opt).unwrap();
+    assert_eq!(reader.len(), 1000);
+
+    for offset in [0_usize, 7, 127, 128, 500, 999] {
+        reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+        let mut buf = [0_u8; 64];
+        let bytes = reader.read(&mut buf).unwrap();
+        assert!(bytes > 0);
+        assert_eq!(&buf[0..bytes], &data[offset..(offset + bytes)]);
+    }
+
+    assert!(reader.seek(SeekFrom::Current(-2000)).is_err());
+}
+
+#[test]
+fn salt_reuse_is_refused() {
+    let opt = encryp_option_builder::new("neko").build().unwrap();
+    let mut encrypted: Vec<u8> = Vec::new();
+    encryp_stream(&mut &b"first"[..], &mut encrypted, &opt, None).unwrap();
+
+    let mut reused: Vec<u8> = Vec::new();
+    assert!(encryp_stream(&mut &b"second"[..], &mut reused, &opt, None).is_err());
+    assert!(encryp_parallel(&mut &b"second"[..], &mut reused, &opt, 6).is_err());
+
+    // whatever the password, the salts alone must not repeat
+    let mut other = opt.clone();
+    other.password = String::from("nya");
+    assert!(encryp_stream(&mut &b"second"[..], &mut reused, &other, None).is_err());
+
+    // nor salt B with another salt A
+    let first = encryp_option_builder::new("neko")
+        .salts(&[1; 16], &[0x9b; 16])
+        .build()
+        .unwrap();
+    encryp_stream(&mut &b"first"[..], &mut Vec::new(), &first, None).unwrap();
+    let second = encryp_option_builder::new("neko")
+        .salts(&[2; 16], &[0x9b; 16])
+        .build()
+        .unwrap();
+    assert!(encryp_stream(&mut &b"second"[..], &mut reused, &second, None).is_err());
+
+    // decrypting does not use up salts
+    assert_eq!(decrypt(&encrypted, &opt).unwrap(), b"first");
+    assert_eq!(decrypt(&encrypted, &opt).unwrap(), b"first");
+}
+
+#[test]
+fn wrong_password_is_rejected() {
+    let data = plaintext(100);
+    let encrypted = encrypt(&data, &option("neko", BUFFER_SIZE), true);
+
+    for password in ["", "nek", "neko ", "Neko"] {
+        assert!(decrypt(&encrypted, &option(password, BUFFER_SIZE)).is_err());
+    }
+
+    let encrypted = encrypt(&data, &option("", BUFFER_SIZE), true);
+    assert!(decrypt(&encrypted, &option("neko", BUFFER_SIZE)).is_err());
+}
+
+#[test]
+fn truncated_container_is_rejected() {
+    let opt = option("neko", BUFFER_SIZE);
+    for known_size in [true, false] {
+        let encrypted = encrypt(&plaintext(100), &opt, known_size);
+        for len in 0..encrypted.len() {
+            assert!(decrypt(&encrypted[0..len], &opt).is_err(), "length {}", len);
+        }
+    }
+}
+
+#[test]
+fn flipped_bit_in_block_content_is_rejected() {
+    let opt = option("neko", BUFFER_SIZE);
+    let encrypted = encrypt(&plaintext(100), &opt, true);
+    let (head, blocks) = split_blocks(&encrypted);
+
+    for (idx, (_, content)) in blocks.iter().enumerate() {
+        for byte in 0..content.len() {
+            for bit in 0..8 {
+                let mut corrupted = blocks.clone();
+                corrupted[idx].1[byte] ^= 1 << bit;
+                let corrupted = join_blocks(&head, &corrupted);
+                assert!(
+                    decrypt(&corrupted, &opt).is_err(),
+                    "block {} byte {} bit {}",
+                    idx,
+                    byte,
+                    bit
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn flipped_block_type_is_rejected() {
+    let opt = option("neko", BUFFER_SIZE);
+    let encrypted = encrypt(&plaintext(100), &opt, true);
+    let (head, blocks) = split_blocks(&encrypted);
+
+    for idx in 0..blocks.len() {
+        let mut corrupted = blocks.clone();
+        corrupted[idx].0 ^= 1;
+        assert!(decrypt(&join_blocks(&head, &corrupted), &opt).is_err());
+    }
+}
+
+#[test]
+fn flipped_file_head_is_rejected() {
+    let opt = option("neko", BUFFER_SIZE);
+    let encrypted = encrypt(&plaintext(100), &opt, true);
+
+    for byte in 0..5 {
+        let mut corrupted = encrypted.clone();
+        corrupted[byte] ^= 0x80;
+        assert!(decrypt(&corrupted, &opt).is_err());
+    }
+}
+
+#[test]
+fn duplicated_block_is_rejected() {
+    let opt = option("neko", BUFFER_SIZE);
+    let encrypted = encrypt(&plaintext(100), &opt, true);
+    let (head, blocks) = split_blocks(&encrypted);
+
+    for idx in 0..blocks.len() {
+        let mut duplicated = blocks.clone();
+        duplicated.insert(idx, blocks[idx].clone());
+        assert!(
+            decrypt(&join_blocks(&head, &duplicated), &opt).is_err(),
+            "block {}",
+            blocks[idx].0
+        );
+    }
+}
+
+#[test]
+fn unknown_blocks_are_skipped() {
+    let opt = option("neko", BUFFER_SIZE);
+    let data = plaintext(100);
+    let encrypted = encrypt(&data, &opt, true);
+    let (head, blocks) = split_blocks(&encrypted);
+
+    for idx in 0..=blocks.len() {
+        let mut extended = blocks.clone();
+        extended.insert(idx, (7777, vec![0x42; 20]));
+        let extended = join_blocks(&head, &extended);
+        assert_eq!(decrypt(&extended, &opt).unwrap(), data);
+    }
+
+    let mut truncated = join_blocks(&head, &blocks);
+    truncated.extend_from_slice(&7777_u64.to_le_bytes());
+    truncated.extend_from_slice(&20_u64.to_le_bytes());
+    truncated.extend_from_slice(&[0x42; 10]);
+    assert!(decrypt(&truncated, &opt).is_err());
+}
+
+#[test]
+fn missing_block_is_rejected() {
+    let opt = option("neko", BUFFER_SIZE);
+    let encrypted = encrypt(&plaintext(100), &opt, true);
+    let (head, blocks) = split_blocks(&encrypted);
+
+    for required in [
+        SALT_A_BLOCK,
+        HASH_PASSWORD_BLOCK,
+        CIPHERTEXT_BLOCK,
+        SHA3_512_BLOCK,
+    ] {
+        let without: Vec<(u64, Vec<u8>)> = blocks
+            .iter()
+            .filter(|(blk_type, _)| *blk_type != required)
+            .cloned()
+            .collect();
+        assert!(decrypt(&join_blocks(&head, &without), &opt).is_err());
+    }
+}
+
+#[test]
+fn flipped_bit_anywhere_is_rejected() {
+    let opt = option("neko", BUFFER_SIZE);
+    for known_size in [true, false] {
+        let encrypted = encrypt(&plaintext(40), &opt, known_size);
+
+        // the file head is checked up to the format name
+        for byte in (0..5).chain(16..encrypted.len()) {
+            for bit in 0..8 {
+                let mut corrupted = encrypted.clone();
+                corrupted[byte] ^= 1 << bit;
+                assert!(
+                    decrypt(&corrupted, &opt).is_err(),
+                    "byte {} bit {}",
+                    byte,
+                    bit
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn oversized_block_is_rejected() {
+    let opt = option("neko", BUFFER_SIZE);
+    let encrypted = encrypt(&plaintext(100), &opt, true);
+
+    for len in [u64::MAX, 1 << 62, 1 << 40] {
+        for offset in [16, block_offset(&encrypted, SHA3_512_BLOCK) - 16] {
+            let mut corrupted = encrypted.clone();
+            corrupted[(offset + 8)..(offset + 16)].copy_from_slice(&len.to_le_bytes());
+            assert!(decrypt(&corrupted, &opt).is_err());
+            assert!(inspect_stream(&mut &corrupted[..]).is_err());
+        }
+    }
+}
+
+#[test]
+fn oversized_ciphertext_length_is_rejected() {
+    let opt = encryp_option_builder::new("neko")
+        .buffer_size(BUFFER_SIZE)
+        .segment_size(128)
+        .threads(2)
+        .build()
+        .unwrap();
+    let encrypted = encrypt(&plaintext(1000), &opt, true);
+    let offset = block_offset(&encrypted, CIPHERTEXT_BLOCK) - 8;
+
+    for len in [u64::MAX, u64::MAX - 100, 1 << 40] {
+        let mut corrupted = encrypted.clone();
+        corrupted[offset..(offset + 8)].copy_from_slice(&len.to_le_bytes());
+
+        assert!(decrypt(&corrupted, &opt).is_err());
+        assert!(decrypt_reader::new(Cursor::new(&corrupted), &opt).is_err());
+        let mut decrypted: Vec<u8> = Vec::new();
+        assert!(decrypt_parallel(&mut Cursor::new(&corrupted), &mut decrypted, &opt).is_err());
+    }
+}
+
+#[test]
+fn oversized_segment_is_rejected() {
+    let too_large = encryp_option_builder::new("neko")
+        .segment_size(1 << 40)
+        .build();
+    assert!(too_large.is_err());
+}
@@ -0,0 +1,82 @@
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use common::*;
+use encryp::{
+    audit_stream, encryp_option_builder, kdf_algorithm, try_upgrade_file, upgrade_stream,
+};
+
+fn modern(password: &str) -> encryp::encryp_option {
+    return encryp_option_builder::new(password)
+        .buffer_size(64)
+        .kdf(kdf_algorithm::argon2id {
+            memory: 64,
+            iterations: 1,
+            parallelism: 1,
+        })
+        .build()
+        .unwrap();
+}
+
+#[test]
+fn upgrade_round_trip() {
+    for (size, known_size) in [(0, true), (100, true), (1000, false)] {
+        let data = plaintext(size);
+        let legacy = encrypt(&data, &option("neko", 64), known_size);
+
+        let opt = modern("neko");
+        let mut upgraded: Vec<u8> = Vec::new();
+        upgrade_stream(
+            &mut legacy.as_slice(),
+            &mut upgraded,
+            &opt,
+            Some(size as u64),
+        )
+        .unwrap();
+
+        assert!(audit_stream(&mut upgraded.as_slice())
+            .unwrap()
+            .kdf
+            .is_some());
+        assert_eq!(decrypt(&upgraded, &opt).unwrap(), data);
+    }
+}
+
+#[test]
+fn upgrade_verifies_old_checksum() {
+    let mut legacy = encrypt(&plaintext(100), &option("neko", 64), true);
+    let last = legacy.len() - 1;
+    legacy[last] ^= 1;
+
+    let mut upgraded: Vec<u8> = Vec::new();
+    let result = upgrade_stream(&mut legacy.as_slice(), &mut upgraded, &modern("neko"), None);
+    assert!(result.is_err());
+
+    let mut upgraded: Vec<u8> = Vec::new();
+    let legacy = encrypt(&plaintext(100), &option("neko", 64), true);
+    let result = upgrade_stream(&mut legacy.as_slice(), &mut upgraded, &modern("nya"), None);
+    assert!(result.is_err());
+}
+
+#[test]
+fn upgrade_file_in_place() {
+    let dir = std::env::temp_dir().join(format!("neko-upgrade-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let name = dir.join("file.neko").to_str().unwrap().to_string();
+
+    let data = plaintext(1000);
+    let legacy = encrypt(&data, &option("neko", 64), true);
+    std::fs::write(&name, &legacy).unwrap();
+
+    assert!(try_upgrade_file(&name, &modern("nya")).is_err());
+    assert_eq!(std::fs::read(&name).unwrap(), legacy);
+
+    try_upgrade_file(&name, &modern("neko")).unwrap();
+    let upgraded = std::fs::read(&name).unwrap();
+    assert_eq!(decrypt(&upgraded, &modern("neko")).unwrap(), data);
+
+    // nothing but the upgraded file is left behind
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}